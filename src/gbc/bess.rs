@@ -0,0 +1,397 @@
+use super::GameboyType;
+use super::save_state::StateError;
+use super::mbc::RTC_DATA_SIZE;
+
+// Best Effort Save State, the block based format SameBoy and other emulators share. A state
+// is a set of memory buffers followed by blocks, each with a 4 letter id and a length. The
+// footer at the very end points at the first block.
+//
+// See https://github.com/LIJI32/SameBoy/blob/master/BESS.md
+
+const FOOTER_MAGIC: &[u8; 4] = b"BESS";
+const BLOCK_HEADER_SIZE: usize = 8;
+
+const CORE_MAJOR_VERSION: u16 = 1;
+const CORE_MINOR_VERSION: u16 = 1;
+const CORE_SIZE: usize = 0xd0;
+const INFO_SIZE: usize = 0x12;
+
+pub const IO_SIZE: usize = 0x80;
+
+pub struct BessState {
+    pub model: GameboyType,
+    // Title and global checksum from the header of the cart the state was made with
+    pub title: Option<[u8; 0x10]>,
+    pub global_checksum: Option<u16>,
+    pub pc: u16,
+    pub af: u16,
+    pub bc: u16,
+    pub de: u16,
+    pub hl: u16,
+    pub sp: u16,
+    pub ime: bool,
+    pub ie: u8,
+    pub halted: bool,
+    // 0xff00-0xff7f, write-only registers hold the last value written
+    pub io: [u8; IO_SIZE],
+    pub ram: Vec<u8>,
+    pub vram: Vec<u8>,
+    pub cart_ram: Vec<u8>,
+    pub oam: Vec<u8>,
+    pub hram: Vec<u8>,
+    pub bg_palettes: Vec<u8>,
+    pub obj_palettes: Vec<u8>,
+    // Writes that put the MBC of a freshly started cart in the saved banking state
+    pub mbc_writes: Vec<(u16, u8)>,
+    pub rtc: Option<[u8; RTC_DATA_SIZE]>,
+}
+
+impl BessState {
+    pub fn new(model: GameboyType) -> BessState {
+        BessState {
+            model,
+            title: None,
+            global_checksum: None,
+            pc: 0,
+            af: 0,
+            bc: 0,
+            de: 0,
+            hl: 0,
+            sp: 0,
+            ime: false,
+            ie: 0,
+            halted: false,
+            io: [0xff; IO_SIZE],
+            ram: Vec::new(),
+            vram: Vec::new(),
+            cart_ram: Vec::new(),
+            oam: Vec::new(),
+            hram: Vec::new(),
+            bg_palettes: Vec::new(),
+            obj_palettes: Vec::new(),
+            mbc_writes: Vec::new(),
+            rtc: None,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+
+        // The buffers come first, CORE refers to them by offset
+        let buffers: Vec<(usize, usize)> = [&self.ram,
+                                            &self.vram,
+                                            &self.cart_ram,
+                                            &self.oam,
+                                            &self.hram,
+                                            &self.bg_palettes,
+                                            &self.obj_palettes]
+            .iter()
+            .map(|buffer| {
+                let offset = bytes.len();
+                bytes.extend_from_slice(buffer);
+                (buffer.len(), offset)
+            })
+            .collect();
+
+        let first_block = bytes.len();
+
+        let name = format!("gbc_rs v{}", env!("CARGO_PKG_VERSION"));
+        write_block(&mut bytes, b"NAME", name.as_bytes());
+
+        if let (Some(title), Some(checksum)) = (self.title, self.global_checksum) {
+            let mut info = Vec::with_capacity(INFO_SIZE);
+            info.extend_from_slice(&title);
+            // The checksum is stored as it is in the cart header
+            info.push((checksum >> 8) as u8);
+            info.push(checksum as u8);
+            write_block(&mut bytes, b"INFO", &info);
+        }
+
+        let mut core = Vec::with_capacity(CORE_SIZE);
+        push_u16(&mut core, CORE_MAJOR_VERSION);
+        push_u16(&mut core, CORE_MINOR_VERSION);
+        core.extend_from_slice(match self.model {
+            GameboyType::Dmg => b"GDB ",
+            GameboyType::Cgb => b"CCE ",
+        });
+        for &reg in [self.pc, self.af, self.bc, self.de, self.hl, self.sp].iter() {
+            push_u16(&mut core, reg)
+        }
+        core.push(self.ime as u8);
+        core.push(self.ie);
+        core.push(self.halted as u8);
+        core.push(0);
+        core.extend_from_slice(&self.io);
+        for &(size, offset) in buffers.iter() {
+            push_u32(&mut core, size as u32);
+            push_u32(&mut core, offset as u32)
+        }
+        write_block(&mut bytes, b"CORE", &core);
+
+        if !self.mbc_writes.is_empty() {
+            let mut mbc = Vec::with_capacity(self.mbc_writes.len() * 3);
+            for &(addr, val) in self.mbc_writes.iter() {
+                push_u16(&mut mbc, addr);
+                mbc.push(val)
+            }
+            write_block(&mut bytes, b"MBC ", &mbc);
+        }
+
+        if let Some(ref rtc) = self.rtc {
+            write_block(&mut bytes, b"RTC ", rtc);
+        }
+
+        write_block(&mut bytes, b"END ", &[]);
+
+        push_u32(&mut bytes, first_block as u32);
+        bytes.extend_from_slice(FOOTER_MAGIC);
+        bytes
+    }
+
+    // Blocks this emulator has no use for are skipped
+    pub fn from_bytes(bytes: &[u8]) -> Result<BessState, StateError> {
+        let len = bytes.len();
+        if len < 8 || &bytes[len - 4..] != FOOTER_MAGIC {
+            return Err(StateError::InvalidFormat);
+        }
+
+        let mut pos = read_u32(bytes, len - 8)? as usize;
+        let mut state = None;
+        let mut title = None;
+        let mut global_checksum = None;
+        let mut mbc_writes = Vec::new();
+        let mut rtc = None;
+
+        loop {
+            let id = slice(bytes, pos, 4)?;
+            let size = read_u32(bytes, pos + 4)? as usize;
+            let data = slice(bytes, pos + BLOCK_HEADER_SIZE, size)?;
+            pos += BLOCK_HEADER_SIZE + size;
+
+            match id {
+                b"END " => break,
+                b"INFO" if size == INFO_SIZE => {
+                    let mut t = [0; 0x10];
+                    t.copy_from_slice(&data[..0x10]);
+                    title = Some(t);
+                    global_checksum = Some(((data[0x10] as u16) << 8) | data[0x11] as u16)
+                }
+                b"CORE" => state = Some(BessState::read_core(bytes, data)?),
+                b"MBC " => {
+                    if size % 3 != 0 {
+                        return Err(StateError::InvalidFormat);
+                    }
+                    for write in data.chunks(3) {
+                        let addr = (write[0] as u16) | ((write[1] as u16) << 8);
+                        mbc_writes.push((addr, write[2]))
+                    }
+                }
+                b"RTC " if size == RTC_DATA_SIZE => {
+                    let mut r = [0; RTC_DATA_SIZE];
+                    r.copy_from_slice(data);
+                    rtc = Some(r)
+                }
+                _ => {}
+            }
+        }
+
+        let mut state = state.ok_or(StateError::InvalidFormat)?;
+        state.title = title;
+        state.global_checksum = global_checksum;
+        state.mbc_writes = mbc_writes;
+        state.rtc = rtc;
+        Ok(state)
+    }
+
+    fn read_core(bytes: &[u8], core: &[u8]) -> Result<BessState, StateError> {
+        if core.len() < CORE_SIZE {
+            return Err(StateError::InvalidFormat);
+        }
+
+        let major = read_u16(core, 0x00)?;
+        if major != CORE_MAJOR_VERSION {
+            return Err(StateError::UnsupportedVersion(major));
+        }
+
+        // Only the model family matters here
+        let model = match core[0x04] {
+            b'G' | b'S' => GameboyType::Dmg,
+            b'C' => GameboyType::Cgb,
+            _ => return Err(StateError::ModelMismatch),
+        };
+
+        let mut state = BessState::new(model);
+        state.pc = read_u16(core, 0x08)?;
+        state.af = read_u16(core, 0x0a)?;
+        state.bc = read_u16(core, 0x0c)?;
+        state.de = read_u16(core, 0x0e)?;
+        state.hl = read_u16(core, 0x10)?;
+        state.sp = read_u16(core, 0x12)?;
+        state.ime = core[0x14] != 0;
+        state.ie = core[0x15];
+        state.halted = core[0x16] == 1;
+        state.io.copy_from_slice(&core[0x18..0x18 + IO_SIZE]);
+
+        let buffer = |index: usize| -> Result<Vec<u8>, StateError> {
+            let entry = 0x18 + IO_SIZE + index * 8;
+            let size = read_u32(core, entry)? as usize;
+            let offset = read_u32(core, entry + 4)? as usize;
+            Ok(slice(bytes, offset, size)?.to_vec())
+        };
+        state.ram = buffer(0)?;
+        state.vram = buffer(1)?;
+        state.cart_ram = buffer(2)?;
+        state.oam = buffer(3)?;
+        state.hram = buffer(4)?;
+        state.bg_palettes = buffer(5)?;
+        state.obj_palettes = buffer(6)?;
+        Ok(state)
+    }
+}
+
+// Copies as much of a saved buffer as fits, the rest of the destination is left as it is
+pub fn copy_buffer(dst: &mut [u8], src: &[u8]) {
+    let len = dst.len().min(src.len());
+    dst[..len].copy_from_slice(&src[..len])
+}
+
+fn write_block(bytes: &mut Vec<u8>, id: &[u8; 4], data: &[u8]) {
+    bytes.extend_from_slice(id);
+    push_u32(bytes, data.len() as u32);
+    bytes.extend_from_slice(data)
+}
+
+fn push_u16(bytes: &mut Vec<u8>, val: u16) {
+    bytes.push(val as u8);
+    bytes.push((val >> 8) as u8)
+}
+
+fn push_u32(bytes: &mut Vec<u8>, val: u32) {
+    push_u16(bytes, val as u16);
+    push_u16(bytes, (val >> 16) as u16)
+}
+
+fn slice(bytes: &[u8], offset: usize, len: usize) -> Result<&[u8], StateError> {
+    match offset.checked_add(len) {
+        Some(end) if end <= bytes.len() => Ok(&bytes[offset..end]),
+        _ => Err(StateError::InvalidFormat),
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Result<u16, StateError> {
+    let b = slice(bytes, offset, 2)?;
+    Ok((b[0] as u16) | ((b[1] as u16) << 8))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, StateError> {
+    let b = slice(bytes, offset, 4)?;
+    Ok((b[0] as u32) | ((b[1] as u32) << 8) | ((b[2] as u32) << 16) | ((b[3] as u32) << 24))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_state() -> BessState {
+        let mut state = BessState::new(GameboyType::Cgb);
+        state.title = Some(*b"TEST TITLE\0\0\0\0\0\x80");
+        state.global_checksum = Some(0x1234);
+        state.pc = 0x0150;
+        state.af = 0x11b0;
+        state.bc = 0x0013;
+        state.de = 0x00d8;
+        state.hl = 0x014d;
+        state.sp = 0xfffe;
+        state.ime = true;
+        state.ie = 0x05;
+        state.halted = true;
+        for (i, io) in state.io.iter_mut().enumerate() {
+            *io = i as u8
+        }
+        state.ram = vec![1; 0x8000];
+        state.vram = vec![2; 0x4000];
+        state.cart_ram = vec![3; 0x2000];
+        state.oam = vec![4; 0xa0];
+        state.hram = vec![5; 0x7f];
+        state.bg_palettes = vec![6; 0x40];
+        state.obj_palettes = vec![7; 0x40];
+        state.mbc_writes = vec![(0x0000, 0x0a), (0x2000, 0x05)];
+        state.rtc = Some([8; RTC_DATA_SIZE]);
+        state
+    }
+
+    #[test]
+    fn roundtrip() {
+        let bytes = test_state().to_bytes();
+        let state = BessState::from_bytes(&bytes).unwrap();
+        let expected = test_state();
+
+        assert_eq!(state.model, expected.model);
+        assert_eq!(state.title, expected.title);
+        assert_eq!(state.global_checksum, expected.global_checksum);
+        assert_eq!([state.pc, state.af, state.bc, state.de, state.hl, state.sp],
+                   [expected.pc, expected.af, expected.bc, expected.de, expected.hl, expected.sp]);
+        assert_eq!((state.ime, state.ie, state.halted),
+                   (expected.ime, expected.ie, expected.halted));
+        assert_eq!(&state.io[..], &expected.io[..]);
+        assert_eq!(state.ram, expected.ram);
+        assert_eq!(state.vram, expected.vram);
+        assert_eq!(state.cart_ram, expected.cart_ram);
+        assert_eq!(state.oam, expected.oam);
+        assert_eq!(state.hram, expected.hram);
+        assert_eq!(state.bg_palettes, expected.bg_palettes);
+        assert_eq!(state.obj_palettes, expected.obj_palettes);
+        assert_eq!(state.mbc_writes, expected.mbc_writes);
+        assert_eq!(state.rtc.map(|rtc| rtc.to_vec()), expected.rtc.map(|rtc| rtc.to_vec()));
+
+        assert_eq!(state.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn optional_blocks_can_be_missing() {
+        let mut state = BessState::new(GameboyType::Dmg);
+        state.ram = vec![1; 0x2000];
+        let state = BessState::from_bytes(&state.to_bytes()).unwrap();
+        assert_eq!(state.model, GameboyType::Dmg);
+        assert_eq!(state.title, None);
+        assert_eq!(state.global_checksum, None);
+        assert!(state.mbc_writes.is_empty());
+        assert!(state.rtc.is_none());
+        assert_eq!(state.ram, vec![1; 0x2000]);
+    }
+
+    #[test]
+    fn bad_footer_is_rejected() {
+        let bytes = test_state().to_bytes();
+        let len = bytes.len();
+
+        let mut bad_magic = bytes.clone();
+        bad_magic[len - 1] = b'X';
+        assert!(BessState::from_bytes(&bad_magic).is_err());
+
+        // The first block offset points past the end
+        let mut bad_offset = bytes.clone();
+        bad_offset[len - 8..len - 4].copy_from_slice(&[0xff; 4]);
+        assert!(BessState::from_bytes(&bad_offset).is_err());
+
+        assert!(BessState::from_bytes(&bytes[len - 4..]).is_err());
+        assert!(BessState::from_bytes(&[]).is_err());
+    }
+
+    #[test]
+    fn truncated_or_damaged_blocks_are_rejected() {
+        let bytes = test_state().to_bytes();
+
+        // Cutting off the END block and the buffers makes the offsets invalid
+        let mut truncated = bytes[..bytes.len() - 16].to_vec();
+        truncated.extend_from_slice(&bytes[bytes.len() - 8..]);
+        assert!(BessState::from_bytes(&truncated).is_err());
+
+        // A state without a CORE block
+        let mut no_core = Vec::new();
+        write_block(&mut no_core, b"END ", &[]);
+        push_u32(&mut no_core, 0);
+        no_core.extend_from_slice(FOOTER_MAGIC);
+        assert!(BessState::from_bytes(&no_core).is_err());
+    }
+}
@@ -7,11 +7,21 @@ use super::mbc::Mbc;
 use super::mbc::MbcType;
 use super::mbc::RamInfo;
 use super::mbc::MbcInfo;
+use super::mbc::{RTC_DATA_SIZE,unix_time};
 use super::GameboyType;
+use super::save_state::{StateWriter,StateReader,StateError};
 
+#[derive(Clone)]
 pub struct Cart {
     bytes: Box<[u8]>,
     mbc: Box<Mbc>,
+    checksum: u32,
+    // Cart RAM changed since it was last saved
+    ram_dirty: bool,
+    // The game disabled RAM after changing it, a good moment to save
+    ram_disabled: bool,
+    // When the RTC in the save file was written
+    rtc_timestamp: Option<u64>,
 }
 
 #[derive(Debug)]
@@ -21,15 +31,58 @@ pub enum DestinationCode {
 }
 
 impl Cart {
-    pub fn new(bytes: Box<[u8]>, ram: Option<Box<[u8]>>) -> Cart {
+    // A save file holds the cart RAM, followed by the RTC for carts with a clock. The RTC is
+    // stored the way VBA and BGB do, older files have a 32-bit timestamp and are 4 bytes
+    // shorter. Other emulators store the RTC differently, any other data after the RAM is
+    // ignored and the RTC starts from zero.
+    pub fn new(bytes: Box<[u8]>, save: Option<Box<[u8]>>) -> Cart {
         let mbc_info = Cart::get_mbc_info(&bytes);
-        let mbc = super::mbc::new_mbc(mbc_info, ram);
+        let ram_size = Cart::get_ram_size(&bytes) as usize;
+
+        let rtc_size = save.as_ref().map_or(0, |save| save.len().saturating_sub(ram_size));
+        let (ram, rtc) = if rtc_size > 0 {
+            let save = save.unwrap();
+            let rtc = if rtc_size == RTC_DATA_SIZE || rtc_size == RTC_DATA_SIZE - 4 {
+                let mut rtc = [0; RTC_DATA_SIZE];
+                rtc[..rtc_size].copy_from_slice(&save[ram_size..]);
+                Some(rtc)
+            } else {
+                None
+            };
+            let ram = if ram_size > 0 {
+                Some(save[..ram_size].to_vec().into_boxed_slice())
+            } else {
+                None
+            };
+            (ram, rtc)
+        } else {
+            (save, None)
+        };
+
+        let mut mbc = super::mbc::new_mbc(mbc_info, ram);
+        let rtc_timestamp = rtc.map(|rtc| {
+            mbc.load_rtc_data(&rtc);
+            rtc[40..].iter().rev().fold(0, |timestamp, &b| (timestamp << 8) | b as u64)
+        });
+        // FNV-1a over the whole ROM
+        let checksum = bytes.iter().fold(0x811c_9dc5, |hash: u32, &b| {
+            (hash ^ b as u32).wrapping_mul(0x0100_0193)
+        });
         Cart {
-            bytes: bytes,
-            mbc: mbc,
+            bytes,
+            mbc,
+            checksum,
+            ram_dirty: false,
+            ram_disabled: false,
+            rtc_timestamp,
         }
     }
 
+    // Identifies the ROM, save states are only loaded into the ROM they were made with
+    pub fn checksum(&self) -> u32 {
+        self.checksum
+    }
+
     pub fn title(&self) -> String {
         let mut title = Vec::new();
         for i in 0x0134..0x0143 {
@@ -49,13 +102,15 @@ impl Cart {
             None
         };
         match bytes[0x0147] {
-            0x00 => MbcInfo::new(MbcType::None, ram_info, false),
-            0x01 => MbcInfo::new(MbcType::Mbc1, ram_info, false),
-            0x02 => MbcInfo::new(MbcType::Mbc1, ram_info, false),
-            0x03 => MbcInfo::new(MbcType::Mbc1, ram_info, true),
-            0x13 => MbcInfo::new(MbcType::Mbc3, ram_info, true),
-            0x19 => MbcInfo::new(MbcType::Mbc5, ram_info, false),
-            0x1b => MbcInfo::new(MbcType::Mbc5, ram_info, true),
+            0x00 => MbcInfo::new(MbcType::None, ram_info, false, false),
+            0x01 => MbcInfo::new(MbcType::Mbc1, ram_info, false, false),
+            0x02 => MbcInfo::new(MbcType::Mbc1, ram_info, false, false),
+            0x03 => MbcInfo::new(MbcType::Mbc1, ram_info, true, false),
+            0x0f | 0x10 => MbcInfo::new(MbcType::Mbc3, ram_info, true, true),
+            0x11 | 0x12 => MbcInfo::new(MbcType::Mbc3, ram_info, false, false),
+            0x13 => MbcInfo::new(MbcType::Mbc3, ram_info, true, false),
+            0x19 => MbcInfo::new(MbcType::Mbc5, ram_info, false, false),
+            0x1b => MbcInfo::new(MbcType::Mbc5, ram_info, true, false),
             _ => panic!("Unsupported mbc_info: 0x{:x}", bytes[0x0147]),
         }
     }
@@ -77,7 +132,6 @@ impl Cart {
         self.rom_size() / (1024 * 16)
     }
 
-    #[allow(dead_code)]
     pub fn ram_size(&self) -> u32 {
         Cart::get_ram_size(&self.bytes)
     }
@@ -93,7 +147,6 @@ impl Cart {
         }
     }
 
-    #[allow(dead_code)]
     pub fn ram_bank_count(&self) -> u32 {
         Cart::get_ram_bank_count(&self.bytes)
     }
@@ -116,21 +169,52 @@ impl Cart {
         }
     }
 
-    #[allow(dead_code)]
     pub fn gameboy_type(&self) -> GameboyType {
         match self.bytes[0x0143] {
-            // TODO: confirm that this is correct
+            // 0x80 - CGB enhanced, but DMG compatible. 0xc0 - CGB only
             0x80 | 0xc0 => GameboyType::Cgb,
             _ => GameboyType::Dmg,
         }
     }
 
+    // Sum of the 16 title bytes, used by the CGB boot ROM to pick a palette for DMG carts
+    pub fn title_checksum(&self) -> u8 {
+        self.bytes[0x0134..0x0144].iter().fold(0, |sum, &b| sum.wrapping_add(b))
+    }
+
+    // The whole 16 byte title area, including the CGB flag
+    pub fn raw_title(&self) -> [u8; 0x10] {
+        let mut title = [0; 0x10];
+        title.copy_from_slice(&self.bytes[0x0134..0x0144]);
+        title
+    }
+
+    pub fn global_checksum(&self) -> u16 {
+        ((self.bytes[0x014e] as u16) << 8) | self.bytes[0x014f] as u16
+    }
+
+    pub fn title_fourth_letter(&self) -> u8 {
+        self.bytes[0x0137]
+    }
+
+    pub fn is_nintendo_licensee(&self) -> bool {
+        match self.bytes[0x014b] {
+            0x01 => true,
+            0x33 => &self.bytes[0x0144..0x0146] == b"01",
+            _ => false,
+        }
+    }
+
     pub fn read(&self, addr: u16) -> u8 {
         self.mbc.read(&self.bytes, addr)
     }
 
     pub fn write(&mut self, addr: u16, val: u8) {
-        self.mbc.write(addr, val)
+        let ram_enabled = self.mbc.ram_enabled();
+        self.mbc.write(addr, val);
+        if ram_enabled && !self.mbc.ram_enabled() && self.ram_dirty {
+            self.ram_disabled = true
+        }
     }
 
     pub fn read_ram(&self, addr: u16) -> u8 {
@@ -138,12 +222,107 @@ impl Cart {
     }
 
     pub fn write_ram(&mut self, addr: u16, val: u8) {
+        if self.mbc.ram_enabled() {
+            self.ram_dirty = true
+        }
         self.mbc.write_ram(addr, val)
     }
 
     pub fn copy_ram(&self) -> Option<Box<[u8]>> {
         self.mbc.copy_ram()
     }
+
+    pub fn ram_mut(&mut self) -> &mut [u8] {
+        self.ram_dirty = true;
+        self.mbc.ram_mut()
+    }
+
+    pub fn has_battery(&self) -> bool {
+        self.mbc_info().has_batt()
+    }
+
+    pub fn has_rtc(&self) -> bool {
+        self.mbc_info().has_rtc()
+    }
+
+    // The contents of the save file, see new
+    pub fn save_data(&self) -> Option<Box<[u8]>> {
+        let ram = self.mbc.copy_ram();
+        match self.mbc.rtc_data() {
+            Some(rtc) => {
+                let mut data = ram.map_or(Vec::new(), |ram| ram.into_vec());
+                data.extend_from_slice(&rtc);
+                Some(data.into_boxed_slice())
+            }
+            None => ram,
+        }
+    }
+
+    // Moves the RTC forward by the host time that passed since the save file was written
+    pub fn sync_rtc(&mut self) {
+        if let Some(timestamp) = self.rtc_timestamp {
+            self.mbc.advance_rtc(unix_time().saturating_sub(timestamp))
+        }
+    }
+
+    // cycle_count is at normal speed, the RTC keeps its rate in double speed mode
+    pub fn cycle_flush(&mut self, cycle_count: u32) {
+        self.mbc.cycle_flush(cycle_count)
+    }
+
+    pub fn ram_dirty(&self) -> bool {
+        self.ram_dirty
+    }
+
+    // Returns true once after the game disabled RAM that has unsaved changes
+    pub fn take_ram_disabled(&mut self) -> bool {
+        let ram_disabled = self.ram_disabled;
+        self.ram_disabled = false;
+        ram_disabled
+    }
+
+    pub fn mark_ram_saved(&mut self) {
+        self.ram_dirty = false;
+        self.ram_disabled = false
+    }
+
+    pub fn bank_writes(&self) -> Vec<(u16, u8)> {
+        self.mbc.bank_writes()
+    }
+
+    pub fn rtc_data(&self) -> Option<[u8; RTC_DATA_SIZE]> {
+        self.mbc.rtc_data()
+    }
+
+    pub fn load_rtc_data(&mut self, data: &[u8; RTC_DATA_SIZE]) {
+        self.mbc.load_rtc_data(data)
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        self.mbc.save_state(state)
+    }
+
+    // Whether the banks selected by replaying these MBC register writes exist on this cart
+    pub fn bank_writes_in_range(&self, writes: &[(u16, u8)]) -> bool {
+        let mut mbc = self.mbc.clone();
+        for &(addr, val) in writes.iter() {
+            mbc.write(addr, val)
+        }
+        mbc.banks_in_range(self.bytes.len())
+    }
+
+    // RAM from a state is older than the save file, loading it doesn't mark RAM as changed.
+    // Otherwise rewinding would write the old RAM over the save file. The state is loaded
+    // into a copy of the MBC, so it is left unchanged when the banks don't exist on this cart.
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let mut mbc = self.mbc.clone();
+        mbc.load_state(state)?;
+        if !mbc.banks_in_range(self.bytes.len()) {
+            return Err(StateError::InvalidFormat);
+        }
+        self.mbc = mbc;
+        Ok(())
+    }
 }
 
 impl Debug for Cart {
@@ -154,12 +333,98 @@ impl Debug for Cart {
     mbc_info: {:?},
     size: {:?},
     bank_count: {:?},
+    ram_size: {:?},
+    ram_bank_count: {:?},
     destination_code: {:?},
 }}",
                self.title(),
                self.mbc_info(),
                self.rom_size(),
                self.rom_bank_count(),
+               self.ram_size(),
+               self.ram_bank_count(),
                self.destination_code())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Header of a state without any component data
+    const STATE_HEADER_SIZE: usize = 11;
+
+    // A 64 KiB ROM with 8 KiB of RAM
+    fn test_cart(cart_type: u8) -> Cart {
+        let mut rom = vec![0; 0x10000];
+        rom[0x0147] = cart_type;
+        rom[0x0148] = 0x01;
+        rom[0x0149] = 0x02;
+        Cart::new(rom.into_boxed_slice(), None)
+    }
+
+    fn save(cart: &Cart) -> Vec<u8> {
+        let mut writer = StateWriter::new(0, 0);
+        cart.save_state(&mut writer);
+        writer.into_bytes()
+    }
+
+    fn load(cart: &mut Cart, bytes: &[u8]) -> Result<(), StateError> {
+        let mut reader = StateReader::new(bytes, 0, 0)?;
+        cart.load_state(&mut reader)?;
+        reader.finish()
+    }
+
+    // Sets the byte at offset in the MBC state, loads it and checks that nothing changed
+    fn assert_rejected(cart_type: u8, offset: usize, val: u8) {
+        let mut cart = test_cart(cart_type);
+        cart.write(0x0000, 0x0a);
+        cart.write_ram(0xa000, 0x42);
+        let before = save(&cart);
+
+        let mut state = before.clone();
+        state[STATE_HEADER_SIZE + offset] = val;
+        assert_eq!(load(&mut cart, &state), Err(StateError::InvalidFormat));
+        assert_eq!(save(&cart), before);
+        assert_eq!(cart.read_ram(0xa000), 0x42);
+    }
+
+    #[test]
+    fn state_roundtrip() {
+        let mut cart = test_cart(0x13);
+        cart.write(0x0000, 0x0a);
+        cart.write(0x2000, 0x03);
+        cart.write_ram(0xa000, 0x42);
+        let state = save(&cart);
+
+        let mut other = test_cart(0x13);
+        assert_eq!(load(&mut other, &state), Ok(()));
+        assert_eq!(save(&other), state);
+        assert_eq!(other.read(0x4000), cart.read(0x4000));
+        assert_eq!(other.read_ram(0xa000), 0x42);
+    }
+
+    #[test]
+    fn unknown_save_trailer_is_ignored() {
+        let mut save = vec![0x42; 0x2000 + 20];
+        save[0x2000..].copy_from_slice(&[0xff; 20]);
+        let mut rom = vec![0; 0x10000];
+        rom[0x0147] = 0x10;
+        rom[0x0148] = 0x01;
+        rom[0x0149] = 0x02;
+        let cart = Cart::new(rom.into_boxed_slice(), Some(save.into_boxed_slice()));
+        assert_eq!(cart.read_ram(0xa000), 0x42);
+        assert_eq!(&cart.rtc_data().unwrap()[..40], &[0; 40][..]);
+    }
+
+    #[test]
+    fn banks_missing_on_the_cart_are_rejected() {
+        // MBC1 ROM bank, MBC3 ROM and RAM bank, MBC5 ROM and RAM bank
+        assert_rejected(0x03, 1, 0x04);
+        assert_rejected(0x13, 1, 0x04);
+        assert_rejected(0x13, 2, 0x02);
+        assert_rejected(0x13, 2, 0x05);
+        assert_rejected(0x1b, 1, 0x04);
+        assert_rejected(0x1b, 3, 0x01);
+    }
+}
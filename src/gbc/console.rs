@@ -1,44 +1,225 @@
 use super::ppu::Ppu;
 use super::spu::Spu;
 use super::cpu::Cpu;
-use super::GameboyType;
-use super::interconnect::Interconnect;
+use super::interconnect::{Interconnect,DMG_BOOT_ROM_SIZE,CGB_BOOT_ROM_SIZE};
+use super::registers::Registers;
+use super::save_state::{StateWriter,StateReader};
+use super::bess::BessState;
 
-pub use super::ppu::VideoSink;
+use std::fmt;
+
+pub use super::ppu::{VideoSink,Renderer,CLKS_SCREEN_REFRESH};
+pub use super::spu::AudioSink;
 pub use super::gamepad::{InputEvent,Gamepad,Button,ButtonState};
 pub use super::cart::Cart;
+pub use super::GameboyType;
+pub use super::palette::{Palette,PalettePreset};
+pub use super::save_state::StateError;
 
 pub struct Console {
     cpu: Cpu,
 }
 
+// The boot ROM doesn't have the size of the boot ROM of the selected model
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct BootRomSizeError {
+    pub expected: usize,
+    pub actual: usize,
+}
+
+impl fmt::Display for BootRomSizeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "boot ROM is {} bytes, expected {} bytes",
+               self.actual,
+               self.expected)
+    }
+}
+
 impl Console {
-    pub fn new(cart: Cart) -> Console {
-        let gb_type = GameboyType::Dmg;
+    // The model is taken from the cart header unless gb_type overrides it. Without a boot ROM
+    // the console starts in the state the boot ROM leaves it in.
+    pub fn new(cart: Cart,
+               gb_type: Option<GameboyType>,
+               boot_rom: Option<Box<[u8]>>)
+               -> Result<Console, BootRomSizeError> {
+        let gb_type = gb_type.unwrap_or(cart.gameboy_type());
+        let dmg_compatibility = gb_type == GameboyType::Cgb &&
+                                cart.gameboy_type() == GameboyType::Dmg;
+
+        let (ppu, reg) = match boot_rom {
+            Some(ref boot_rom) => {
+                let expected_size = match gb_type {
+                    GameboyType::Cgb => CGB_BOOT_ROM_SIZE,
+                    GameboyType::Dmg => DMG_BOOT_ROM_SIZE,
+                };
+                if boot_rom.len() != expected_size {
+                    return Err(BootRomSizeError {
+                        expected: expected_size,
+                        actual: boot_rom.len(),
+                    });
+                }
+                (Ppu::power_on(gb_type), Registers::power_on())
+            }
+            None => {
+                let mut ppu = Ppu::new(gb_type);
+                if dmg_compatibility {
+                    // Colorize the cart the way the boot ROM would have
+                    ppu.set_dmg_compatibility(true);
+                    ppu.set_dmg_palette(Palette::cgb_colorization(&cart))
+                }
+                (ppu, Registers::new(gb_type))
+            }
+        };
+
         let interconnect = Interconnect::new(
             gb_type,
+            boot_rom,
             cart,
-            Ppu::new(),
+            ppu,
             Spu::new(),
             Gamepad::new());
-        Console {
-            cpu: Cpu::new(gb_type, interconnect)
-        }
+        Ok(Console {
+            cpu: Cpu::new(reg, interconnect)
+        })
     }
 
-    pub fn run_for_one_frame(&mut self, video_sink: &mut dyn VideoSink) {
+    pub fn run_for_one_frame(&mut self,
+                             video_sink: &mut dyn VideoSink,
+                             audio_sink: &mut dyn AudioSink) {
+        self.cpu.interconnect.spu.set_sample_rate(audio_sink.sample_rate());
+
         let mut frame_handler = FrameHandler::new(video_sink);
         while !frame_handler.frame_available {
             self.cpu.step(&mut frame_handler);
         }
+
+        self.cpu.interconnect.spu.flush_samples(audio_sink)
+    }
+
+    // Colors for DMG graphics, also used for DMG carts running on a CGB
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.cpu.interconnect.ppu.set_dmg_palette(palette)
+    }
+
+    pub fn preset_palette(&self, preset: PalettePreset) -> Palette {
+        preset.palette(&self.cpu.interconnect.cart)
+    }
+
+    // The scanline renderer is faster, the pixel FIFO renderer handles mid-line effects
+    pub fn set_renderer(&mut self, renderer: Renderer) {
+        self.cpu.interconnect.ppu.set_renderer(renderer)
+    }
+
+    // Enhancement that draws every sprite on a line instead of only the first 10, this
+    // removes flicker in games that multiplex sprites
+    pub fn set_sprite_limit(&mut self, sprite_limit: bool) {
+        self.cpu.interconnect.ppu.set_sprite_limit(sprite_limit)
+    }
+
+    // VRAM and OAM are inaccessible to the CPU while the PPU reads them, like on hardware.
+    // Turning this off helps when debugging games that do not wait for the right mode.
+    pub fn set_access_restrictions(&mut self, access_restrictions: bool) {
+        self.cpu.interconnect.ppu.set_access_restrictions(access_restrictions)
     }
 
     pub fn handle_event(&mut self, input_event: InputEvent) {
         self.cpu.interconnect.gamepad.handle_event(input_event)
     }
 
-    pub fn copy_cart_ram(&self) -> Option<Box<[u8]>> {
-        self.cpu.interconnect.cart.copy_ram()
+    // Contents for the .sav file: cart RAM, followed by the RTC for carts with a clock
+    pub fn copy_save_data(&self) -> Option<Box<[u8]>> {
+        self.cpu.interconnect.cart.save_data()
+    }
+
+    // The RTC keeps running while the game is not played, so it should be saved on exit
+    pub fn has_rtc(&self) -> bool {
+        self.cpu.interconnect.cart.has_rtc()
+    }
+
+    // Moves the RTC forward by the host time that passed since the save file was written.
+    // Without this the clock only counts emulated time.
+    pub fn sync_rtc(&mut self) {
+        self.cpu.interconnect.cart.sync_rtc()
+    }
+
+    // Whether cart RAM is kept by a battery, and so worth saving
+    pub fn has_battery(&self) -> bool {
+        self.cpu.interconnect.cart.has_battery()
+    }
+
+    // Cart RAM changed since mark_cart_ram_saved was called
+    pub fn cart_ram_dirty(&self) -> bool {
+        self.cpu.interconnect.cart.ram_dirty()
+    }
+
+    // Games disable cart RAM when they are done saving. Returns true once after that happened
+    // to RAM with unsaved changes.
+    pub fn take_cart_ram_disabled(&mut self) -> bool {
+        self.cpu.interconnect.cart.take_ram_disabled()
+    }
+
+    pub fn mark_cart_ram_saved(&mut self) {
+        self.cpu.interconnect.cart.mark_ram_saved()
+    }
+
+    // Snapshot of the whole console, including cart RAM and the RTC. Frontend settings like
+    // the palette and renderer are not included.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new(self.model_id(), self.cpu.interconnect.cart.checksum());
+        self.cpu.save_state(&mut state);
+        state.into_bytes()
+    }
+
+    // States are only accepted from the same ROM and model. The state is loaded into a copy
+    // of the console, so the console is left unchanged when loading fails.
+    pub fn load_state(&mut self, bytes: &[u8]) -> Result<(), StateError> {
+        let mut state = StateReader::new(bytes,
+                                         self.model_id(),
+                                         self.cpu.interconnect.cart.checksum())?;
+        let mut cpu = self.cpu.clone();
+        cpu.load_state(&mut state)?;
+        state.finish()?;
+        self.cpu = cpu;
+        Ok(())
+    }
+
+    // Exports a BESS state that other emulators can load. Internal timing state is not part
+    // of the format, so the result is less exact than save_state.
+    pub fn export_bess(&self) -> Vec<u8> {
+        let cart = &self.cpu.interconnect.cart;
+        let mut bess = BessState::new(self.cpu.interconnect.gameboy_type());
+        bess.title = Some(cart.raw_title());
+        bess.global_checksum = Some(cart.global_checksum());
+        self.cpu.save_bess(&mut bess);
+        bess.to_bytes()
+    }
+
+    // Imports a BESS state made by this or another emulator. The INFO block must match the
+    // ROM, states without one could be for any game. Like load_state the state is imported
+    // into a copy of the console, so the console is left unchanged when importing fails.
+    pub fn import_bess(&mut self, bytes: &[u8]) -> Result<(), StateError> {
+        let bess = BessState::from_bytes(bytes)?;
+        if bess.model != self.cpu.interconnect.gameboy_type() {
+            return Err(StateError::ModelMismatch);
+        }
+        let cart = &self.cpu.interconnect.cart;
+        if bess.title != Some(cart.raw_title()) ||
+           bess.global_checksum != Some(cart.global_checksum()) {
+            return Err(StateError::RomMismatch);
+        }
+
+        let mut cpu = self.cpu.clone();
+        cpu.load_bess(&bess)?;
+        self.cpu = cpu;
+        Ok(())
+    }
+
+    fn model_id(&self) -> u8 {
+        match self.cpu.interconnect.gameboy_type() {
+            GameboyType::Dmg => 0,
+            GameboyType::Cgb => 1,
+        }
     }
 }
 
@@ -57,8 +238,161 @@ impl<'a> FrameHandler<'a> {
 }
 
 impl<'a> VideoSink for FrameHandler<'a> {
-    fn frame_available(&mut self, frame: &Box<[u32]>) {
+    fn frame_available(&mut self, frame: &[u32]) {
         self.video_sink.frame_available(frame);
         self.frame_available = true
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct NoVideo;
+
+    impl VideoSink for NoVideo {
+        fn frame_available(&mut self, _frame: &[u32]) {}
+    }
+
+    struct NoAudio;
+
+    impl AudioSink for NoAudio {
+        fn sample_rate(&self) -> u32 {
+            0
+        }
+
+        fn samples_available(&mut self, _samples: &[i16]) {}
+    }
+
+    // A cart that loops at 0x0100
+    fn test_cart() -> Cart {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0100] = 0x18;
+        rom[0x0101] = 0xfe;
+        Cart::new(rom.into_boxed_slice(), None)
+    }
+
+    // A boot ROM that runs NOPs up to 0x00fc, then unmaps itself
+    fn test_boot_rom() -> Box<[u8]> {
+        let mut boot_rom = vec![0; DMG_BOOT_ROM_SIZE];
+        boot_rom[0xfc..].copy_from_slice(&[0x3e, 0x01, 0xe0, 0x50]);
+        boot_rom.into_boxed_slice()
+    }
+
+    fn dmg_console(boot_rom: Option<Box<[u8]>>) -> Console {
+        Console::new(test_cart(), Some(GameboyType::Dmg), boot_rom).unwrap()
+    }
+
+    fn run_frame(console: &mut Console) {
+        console.run_for_one_frame(&mut NoVideo, &mut NoAudio)
+    }
+
+    #[test]
+    fn state_from_boot_sequence_maps_boot_rom_again() {
+        let mut console = dmg_console(Some(test_boot_rom()));
+        let during_boot = console.save_state();
+        run_frame(&mut console);
+        assert!(!console.cpu.interconnect.boot_rom_mapped());
+
+        assert_eq!(console.load_state(&during_boot), Ok(()));
+        assert!(console.cpu.interconnect.boot_rom_mapped());
+        assert_eq!(console.save_state(), during_boot);
+    }
+
+    #[test]
+    fn failed_load_leaves_console_unchanged() {
+        let mut console = dmg_console(Some(test_boot_rom()));
+        let during_boot = console.save_state();
+        run_frame(&mut console);
+        let after_boot = console.save_state();
+        console.load_state(&during_boot).unwrap();
+
+        // These states unmap the boot ROM before they run out
+        for &len in [after_boot.len() / 2, after_boot.len() - 1].iter() {
+            assert_eq!(console.load_state(&after_boot[..len]), Err(StateError::InvalidFormat));
+            assert_eq!(console.save_state(), during_boot);
+        }
+
+        let mut corrupt = after_boot.clone();
+        corrupt.push(0);
+        assert_eq!(console.load_state(&corrupt), Err(StateError::InvalidFormat));
+        assert_eq!(console.save_state(), during_boot);
+
+        assert_eq!(console.load_state(&after_boot), Ok(()));
+        assert_eq!(console.save_state(), after_boot);
+    }
+
+    // A console running a cart with MBC1 and 8 KiB of battery backed RAM
+    fn mbc1_console() -> Console {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0100] = 0x18;
+        rom[0x0101] = 0xfe;
+        rom[0x0147] = 0x03;
+        rom[0x0149] = 0x02;
+        let cart = Cart::new(rom.into_boxed_slice(), None);
+        Console::new(cart, Some(GameboyType::Dmg), None).unwrap()
+    }
+
+    #[test]
+    fn loading_state_keeps_saved_cart_ram() {
+        let mut console = mbc1_console();
+        console.cpu.interconnect.cart.write(0x0000, 0x0a);
+        console.cpu.interconnect.cart.write_ram(0xa000, 0x42);
+        assert!(console.cart_ram_dirty());
+
+        let state = console.save_state();
+        console.mark_cart_ram_saved();
+        assert_eq!(console.load_state(&state), Ok(()));
+        assert!(!console.cart_ram_dirty());
+    }
+
+    #[test]
+    fn bess_import_needs_matching_rom() {
+        let mut console = dmg_console(None);
+        let exported = console.export_bess();
+        let mut bess = BessState::from_bytes(&exported).unwrap();
+
+        bess.title = None;
+        bess.global_checksum = None;
+        assert_eq!(console.import_bess(&bess.to_bytes()), Err(StateError::RomMismatch));
+
+        bess.title = Some(*b"OTHER GAME\0\0\0\0\0\0");
+        bess.global_checksum = Some(0);
+        assert_eq!(console.import_bess(&bess.to_bytes()), Err(StateError::RomMismatch));
+
+        assert_eq!(console.import_bess(&exported), Ok(()));
+    }
+
+    #[test]
+    fn bess_import_rejects_missing_banks() {
+        let mut console = mbc1_console();
+        let before = console.save_state();
+        let mut bess = BessState::from_bytes(&console.export_bess()).unwrap();
+
+        // The 32 KiB ROM only has banks 0 and 1
+        bess.mbc_writes.push((0x2000, 0x04));
+        assert_eq!(console.import_bess(&bess.to_bytes()), Err(StateError::InvalidFormat));
+        assert_eq!(console.save_state(), before);
+    }
+
+    #[test]
+    fn wrong_boot_rom_size_is_rejected() {
+        let boot_rom = vec![0; DMG_BOOT_ROM_SIZE].into_boxed_slice();
+        let error = Console::new(test_cart(), Some(GameboyType::Cgb), Some(boot_rom)).err();
+        assert_eq!(error,
+                   Some(BootRomSizeError {
+                       expected: CGB_BOOT_ROM_SIZE,
+                       actual: DMG_BOOT_ROM_SIZE,
+                   }));
+    }
+
+    #[test]
+    fn boot_sequence_state_needs_boot_rom() {
+        let with_boot_rom = dmg_console(Some(test_boot_rom()));
+        let during_boot = with_boot_rom.save_state();
+
+        let mut console = dmg_console(None);
+        let before = console.save_state();
+        assert_eq!(console.load_state(&during_boot), Err(StateError::BootRomMissing));
+        assert_eq!(console.save_state(), before);
+    }
+}
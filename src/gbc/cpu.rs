@@ -1,17 +1,31 @@
 use super::interconnect::Interconnect;
 use super::registers::{Registers, Reg8, Reg16};
 use super::opcode::{CB_OPCODE_TIMES, OPCODE_TIMES, OPCODE_COND_TIMES};
-use super::GameboyType;
 use super::ppu::VideoSink;
+use super::save_state::{StateWriter,StateReader,StateError};
+use super::bess::BessState;
 
 use std::u8;
 use std::u16;
 
+// The CPU is stopped for 2050 M-cycles while the clock speed changes
+const SPEED_SWITCH_CYCLES: u32 = 2050;
+
+// Every memory access takes one M-cycle, the rest of the system is advanced after each one
+const M_CYCLE: u32 = 4;
+
+#[derive(Clone)]
 pub struct Cpu {
     reg: Registers,
     pub interconnect: Interconnect,
     ime: bool,
+    // EI enables interrupts after the instruction that follows it
+    ime_scheduled: bool,
     halted: bool,
+    // HALT with IME off and an interrupt pending does not increment PC past the next opcode
+    halt_bug: bool,
+    // Cycles the rest of the system has been advanced by during the current step
+    step_cycles: u32,
 }
 
 struct Imm8;
@@ -49,6 +63,7 @@ enum Timing {
     Default,
     Cond,
     Cb(u32),
+    Fixed(u32),
 }
 
 trait Src<T> {
@@ -100,7 +115,7 @@ impl Src<u8> for ZMem<Imm8> {
         let ZMem(imm) = self;
         let offset = imm.read(cpu) as u16;
         let addr = 0xff00 + offset;
-        cpu.interconnect.read(addr)
+        cpu.read(addr)
     }
 }
 
@@ -109,7 +124,7 @@ impl Dst<u8> for ZMem<Imm8> {
         let ZMem(imm) = self;
         let offset = imm.read(cpu) as u16;
         let addr = 0xff00 + offset;
-        cpu.interconnect.write(addr, val)
+        cpu.write(addr, val)
     }
 }
 
@@ -118,7 +133,7 @@ impl Src<u8> for ZMem<Reg8> {
         let ZMem(reg) = self;
         let offset = reg.read(cpu) as u16;
         let addr = 0xff00 + offset;
-        cpu.interconnect.read(addr)
+        cpu.read(addr)
     }
 }
 
@@ -127,7 +142,7 @@ impl Dst<u8> for ZMem<Reg8> {
         let ZMem(reg) = self;
         let offset = reg.read(cpu) as u16;
         let addr = 0xff00 + offset;
-        cpu.interconnect.write(addr, val)
+        cpu.write(addr, val)
     }
 }
 
@@ -135,7 +150,7 @@ impl Dst<u8> for Mem<Reg16> {
     fn write(self, cpu: &mut Cpu, val: u8) {
         let Mem(reg) = self;
         let addr = reg.read(cpu);
-        cpu.interconnect.write(addr, val)
+        cpu.write(addr, val)
     }
 }
 
@@ -143,7 +158,7 @@ impl Dst<u8> for Mem<Imm16> {
     fn write(self, cpu: &mut Cpu, val: u8) {
         let Mem(imm) = self;
         let addr = imm.read(cpu);
-        cpu.interconnect.write(addr, val)
+        cpu.write(addr, val)
     }
 }
 
@@ -151,7 +166,7 @@ impl Src<u8> for Mem<Imm16> {
     fn read(self, cpu: &mut Cpu) -> u8 {
         let Mem(imm) = self;
         let addr = imm.read(cpu);
-        cpu.interconnect.read(addr)
+        cpu.read(addr)
     }
 }
 
@@ -161,8 +176,8 @@ impl Dst<u16> for Mem<Imm16> {
         let addr = imm.read(cpu);
         let l = val as u8;
         let h = (val >> 8) as u8;
-        cpu.interconnect.write(addr, l);
-        cpu.interconnect.write(addr + 1, h)
+        cpu.write(addr, l);
+        cpu.write(addr + 1, h)
     }
 }
 
@@ -170,30 +185,132 @@ impl Src<u8> for Mem<Reg16> {
     fn read(self, cpu: &mut Cpu) -> u8 {
         let Mem(reg) = self;
         let addr = reg.read(cpu);
-        cpu.interconnect.read(addr)
+        cpu.read(addr)
     }
 }
 
 impl Cpu {
-    pub fn new(gb_type: GameboyType, interconnect: Interconnect) -> Cpu {
+    pub fn new(reg: Registers, interconnect: Interconnect) -> Cpu {
+        // The CPU powers on with IME cleared, without a boot ROM it starts as the boot ROM
+        // leaves it, with IME set
+        let ime = !interconnect.boot_rom_mapped();
         Cpu {
-            reg: Registers::new(gb_type),
-            interconnect: interconnect,
-            ime: true,
+            reg,
+            interconnect,
+            ime,
+            ime_scheduled: false,
             halted: false,
+            halt_bug: false,
+            step_cycles: 0,
         }
     }
 
     pub fn step(&mut self, video_sink: &mut dyn VideoSink) -> u32 {
-        let elapsed_cycles = {
-            self.handle_interrupt() + self.execute_instruction()
-        };
-        self.interconnect.cycle_flush(elapsed_cycles, video_sink);
+        self.step_cycles = 0;
+        let mut elapsed_cycles = self.handle_interrupt();
+        if self.ime_scheduled {
+            self.ime = true;
+            self.ime_scheduled = false
+        }
+        elapsed_cycles += self.execute_instruction();
+
+        // Internal cycles that do not access memory
+        while self.step_cycles < elapsed_cycles {
+            self.tick()
+        }
+        self.flush_frame(video_sink);
+
+        // VRAM DMA halts the CPU while the rest of the system keeps running
+        loop {
+            let stall_cycles = self.interconnect.take_dma_stall_cycles();
+            if stall_cycles == 0 {
+                break;
+            }
+            self.interconnect.cycle_flush(stall_cycles);
+            self.flush_frame(video_sink);
+            elapsed_cycles += stall_cycles
+        }
+
         elapsed_cycles
     }
 
+    // The state is taken between instructions, so the interconnect holds all other state
+    pub fn save_state(&self, state: &mut StateWriter) {
+        self.reg.save_state(state);
+        state.write_bool(self.ime);
+        state.write_bool(self.ime_scheduled);
+        state.write_bool(self.halted);
+        state.write_bool(self.halt_bug);
+        self.interconnect.save_state(state)
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.reg.load_state(state)?;
+        self.ime = state.read_bool()?;
+        self.ime_scheduled = state.read_bool()?;
+        self.halted = state.read_bool()?;
+        self.halt_bug = state.read_bool()?;
+        self.interconnect.load_state(state)
+    }
+
+    pub fn save_bess(&self, bess: &mut BessState) {
+        bess.pc = self.reg.pc;
+        bess.af = self.reg.read_u16(Reg16::AF);
+        bess.bc = self.reg.read_u16(Reg16::BC);
+        bess.de = self.reg.read_u16(Reg16::DE);
+        bess.hl = self.reg.read_u16(Reg16::HL);
+        bess.sp = self.reg.sp;
+        // A pending EI takes effect before the next instruction either way
+        bess.ime = self.ime || self.ime_scheduled;
+        bess.halted = self.halted;
+        self.interconnect.save_bess(bess)
+    }
+
+    pub fn load_bess(&mut self, bess: &BessState) -> Result<(), StateError> {
+        self.reg.pc = bess.pc;
+        self.reg.write_u16(Reg16::AF, bess.af);
+        self.reg.write_u16(Reg16::BC, bess.bc);
+        self.reg.write_u16(Reg16::DE, bess.de);
+        self.reg.write_u16(Reg16::HL, bess.hl);
+        self.reg.sp = bess.sp;
+        self.ime = bess.ime;
+        self.ime_scheduled = false;
+        self.halted = bess.halted;
+        self.halt_bug = false;
+        self.interconnect.load_bess(bess)
+    }
+
+    fn flush_frame(&mut self, video_sink: &mut dyn VideoSink) {
+        if let Some(frame) = self.interconnect.ppu.take_frame() {
+            video_sink.frame_available(frame)
+        }
+    }
+
+    // Advances the rest of the system by one M-cycle
+    fn tick(&mut self) {
+        self.interconnect.cycle_flush(M_CYCLE);
+        self.step_cycles += M_CYCLE
+    }
+
+    fn read(&mut self, addr: u16) -> u8 {
+        let value = self.interconnect.read(addr);
+        self.tick();
+        value
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        self.interconnect.write(addr, val);
+        self.tick()
+    }
+
+    fn pending_interrupts(&self) -> u8 {
+        self.interconnect.int_flags & self.interconnect.int_enable & 0b1_1111
+    }
+
+    // Dispatching an interrupt takes 5 M-cycles: 2 wait cycles, 2 cycles pushing PC and 1
+    // cycle jumping to the handler
     fn handle_interrupt(&mut self) -> u32 {
-        let ints = self.interconnect.int_flags & self.interconnect.int_enable;
+        let ints = self.pending_interrupts();
 
         if self.halted {
             self.halted = ints == 0;
@@ -208,9 +325,23 @@ impl Cpu {
         }
 
         self.ime = false;
+        self.ime_scheduled = false;
+
+        self.tick();
+        self.tick();
+
+        let pc = self.reg.pc;
+        self.push_u8((pc >> 8) as u8);
 
-        let int = ints.trailing_zeros();
-        let int_handler = {
+        // The interrupt is picked after the high byte of PC is pushed. If that push overwrote
+        // IE and no enabled interrupt is left pending, the dispatch is cancelled and the CPU
+        // jumps to 0x0000 instead.
+        let ints = self.pending_interrupts();
+        self.push_u8(pc as u8);
+
+        let int_handler = if ints != 0 {
+            let int = ints.trailing_zeros();
+            self.interconnect.int_flags &= !(0b1 << int);
             match int {
                 0 => 0x40,// VBLANK
                 1 => 0x48,// LCDC STATUS
@@ -219,19 +350,18 @@ impl Cpu {
                 4 => 0x60,// P10-P13 INPUT SIGNAL
                 _ => panic!("Invalid interrupt {:x}", int),
             }
+        } else {
+            0x0000
         };
 
-        self.interconnect.int_flags &= 0xff << (int + 1);
-
-        let pc = self.reg.pc;
-        self.push_u16(pc);
         self.reg.pc = int_handler;
+        self.tick();
 
         20
     }
 
     fn execute_instruction(&mut self) -> u32 {
-        let opcode = if !self.halted { self.fetch_u8() } else { 0 };
+        let opcode = if !self.halted { self.fetch_opcode() } else { 0 };
 
         use super::registers::Reg8::*;
         use super::registers::Reg16::*;
@@ -493,6 +623,7 @@ impl Cpu {
             Timing::Default => OPCODE_TIMES[opcode as usize] as u32,
             Timing::Cond => OPCODE_COND_TIMES[opcode as usize] as u32,
             Timing::Cb(x) => x,
+            Timing::Fixed(x) => x,
         };
         cycles * 4
     }
@@ -769,24 +900,36 @@ impl Cpu {
         Timing::Cb(CB_OPCODE_TIMES[opcode as usize] as u32)
     }
 
-    fn stop(&self) -> Timing {
+    fn stop(&mut self) -> Timing {
         // http://www.pastraiser.com/cpu/gameboy/gameboy_opcodes.html
         //
         // Instruction STOP has according to manuals opcode 10 00 and
         // thus is 2 bytes long. Anyhow it seems there is no reason for
         // it so some assemblers code it simply as one byte instruction 10
         //
-        Timing::Default
+        // On CGB, STOP performs the speed switch prepared through KEY1
+        if self.interconnect.speed_switch() {
+            Timing::Fixed(SPEED_SWITCH_CYCLES)
+        } else {
+            Timing::Default
+        }
     }
 
     fn halt(&mut self) -> Timing {
-        self.halted = true;
+        // With IME off HALT exits as soon as an interrupt is pending, without servicing it.
+        // If one is already pending the CPU does not halt and reads the next byte twice.
+        if !self.ime && self.pending_interrupts() != 0 {
+            self.halt_bug = true
+        } else {
+            self.halted = true
+        }
         Timing::Default
     }
 
     fn call<S: Src<u16>>(&mut self, cond: Cond, src: S) -> Timing {
         let new_pc = src.read(self);
         if cond.is_true(self) {
+            self.tick();
             let ret = self.reg.pc;
             self.push_u16(ret);
             self.reg.pc = new_pc;
@@ -797,6 +940,11 @@ impl Cpu {
     }
 
     fn ret(&mut self, cond: Cond) -> Timing {
+        // The condition is checked in an extra M-cycle
+        match cond {
+            Cond::Uncond => {}
+            _ => self.tick(),
+        }
         if cond.is_true(self) {
             let new_pc = self.pop_u16();
             self.reg.pc = new_pc;
@@ -807,11 +955,13 @@ impl Cpu {
     }
 
     fn reti(&mut self) -> Timing {
-        self.ei();
+        // Unlike EI, RETI enables interrupts right away
+        self.ime = true;
         self.ret(Cond::Uncond)
     }
 
     fn rst(&mut self, p: u8) -> Timing {
+        self.tick();
         let pc = self.reg.pc;
         self.push_u16(pc);
         self.reg.pc = p as u16;
@@ -1200,6 +1350,7 @@ impl Cpu {
 
     fn push<S: Src<u16>>(&mut self, src: S) -> Timing {
         let value = src.read(self);
+        self.tick();
         self.push_u16(value);
         Timing::Default
     }
@@ -1212,17 +1363,28 @@ impl Cpu {
 
     fn di(&mut self) -> Timing {
         self.ime = false;
+        self.ime_scheduled = false;
         Timing::Default
     }
 
     fn ei(&mut self) -> Timing {
-        self.ime = true;
+        self.ime_scheduled = true;
         Timing::Default
     }
 
+    fn fetch_opcode(&mut self) -> u8 {
+        if self.halt_bug {
+            self.halt_bug = false;
+            let pc = self.reg.pc;
+            self.read(pc)
+        } else {
+            self.fetch_u8()
+        }
+    }
+
     fn fetch_u8(&mut self) -> u8 {
         let pc = self.reg.pc;
-        let value = self.interconnect.read(pc);
+        let value = self.read(pc);
         self.reg.pc = pc.wrapping_add(1);
         value
     }
@@ -1235,7 +1397,7 @@ impl Cpu {
 
     fn push_u8(&mut self, value: u8) {
         let sp = self.reg.sp.wrapping_sub(1);
-        self.interconnect.write(sp, value);
+        self.write(sp, value);
         self.reg.sp = sp
     }
 
@@ -1246,7 +1408,7 @@ impl Cpu {
 
     fn pop_u8(&mut self) -> u8 {
         let sp = self.reg.sp;
-        let value = self.interconnect.read(sp);
+        let value = self.read(sp);
         self.reg.sp = sp.wrapping_add(1);
         value
     }
@@ -1257,3 +1419,131 @@ impl Cpu {
         (high << 8) | low
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::GameboyType;
+    use super::super::INT_TIMEROVERFLOW;
+    use super::super::cart::Cart;
+    use super::super::ppu::Ppu;
+    use super::super::spu::Spu;
+    use super::super::gamepad::Gamepad;
+
+    const TIMER_VECTOR: u16 = 0x50;
+
+    struct NoVideo;
+
+    impl VideoSink for NoVideo {
+        fn frame_available(&mut self, _frame: &[u32]) {}
+    }
+
+    // A CPU about to run program from 0x0150, with IME off and the timer interrupt pending
+    fn cpu_with_program(program: &[u8]) -> Cpu {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0150..0x0150 + program.len()].copy_from_slice(program);
+        let cart = Cart::new(rom.into_boxed_slice(), None);
+        let interconnect = Interconnect::new(GameboyType::Dmg,
+                                             None,
+                                             cart,
+                                             Ppu::new(GameboyType::Dmg),
+                                             Spu::new(),
+                                             Gamepad::new());
+        let mut cpu = Cpu::new(Registers::new(GameboyType::Dmg), interconnect);
+        cpu.reg.pc = 0x0150;
+        cpu.ime = false;
+        cpu.interconnect.int_enable = INT_TIMEROVERFLOW.bits();
+        cpu.interconnect.int_flags = INT_TIMEROVERFLOW.bits();
+        cpu
+    }
+
+    fn step(cpu: &mut Cpu, count: usize) {
+        for _ in 0..count {
+            cpu.step(&mut NoVideo);
+        }
+    }
+
+    #[test]
+    fn halt_bug_repeats_next_byte() {
+        // HALT, INC A
+        let mut cpu = cpu_with_program(&[0x76, 0x3c]);
+        cpu.reg.a = 0;
+        step(&mut cpu, 3);
+        assert_eq!(cpu.reg.a, 2);
+        assert_eq!(cpu.reg.pc, 0x0152);
+        assert!(!cpu.halted);
+    }
+
+    #[test]
+    fn halt_without_ime_wakes_without_dispatch() {
+        // HALT, INC A
+        let mut cpu = cpu_with_program(&[0x76, 0x3c]);
+        cpu.interconnect.int_flags = 0;
+        cpu.reg.a = 0;
+        step(&mut cpu, 2);
+        assert!(cpu.halted);
+        assert_eq!(cpu.reg.pc, 0x0151);
+
+        cpu.interconnect.int_flags = INT_TIMEROVERFLOW.bits();
+        step(&mut cpu, 1);
+        assert!(!cpu.halted);
+        assert_eq!(cpu.reg.a, 1);
+        assert_eq!(cpu.reg.pc, 0x0152);
+    }
+
+    #[test]
+    fn ei_takes_effect_after_next_instruction() {
+        // EI, INC A, INC A
+        let mut cpu = cpu_with_program(&[0xfb, 0x3c, 0x3c]);
+        cpu.reg.a = 0;
+        cpu.reg.sp = 0xfffe;
+        step(&mut cpu, 2);
+        assert_eq!(cpu.reg.a, 1);
+        assert_eq!(cpu.reg.pc, 0x0152);
+
+        // The interrupt is taken before the second INC A, then a NOP at the vector runs
+        step(&mut cpu, 1);
+        assert_eq!(cpu.reg.a, 1);
+        assert_eq!(cpu.reg.pc, TIMER_VECTOR + 1);
+        assert_eq!(cpu.reg.sp, 0xfffc);
+        assert_eq!(cpu.interconnect.read(0xfffc), 0x52);
+        assert_eq!(cpu.interconnect.read(0xfffd), 0x01);
+    }
+
+    #[test]
+    fn ei_di_blocks_interrupt() {
+        // EI, DI, INC A
+        let mut cpu = cpu_with_program(&[0xfb, 0xf3, 0x3c]);
+        cpu.reg.a = 0;
+        step(&mut cpu, 3);
+        assert_eq!(cpu.reg.a, 1);
+        assert_eq!(cpu.reg.pc, 0x0153);
+    }
+
+    #[test]
+    fn ie_push_cancels_dispatch() {
+        let mut cpu = cpu_with_program(&[]);
+        cpu.ime = true;
+        // Pushing PC high byte 0x01 to IE leaves only VBLANK enabled
+        cpu.reg.sp = 0x0000;
+        step(&mut cpu, 1);
+        assert_eq!(cpu.interconnect.int_enable, 0x01);
+        assert_eq!(cpu.interconnect.int_flags & INT_TIMEROVERFLOW.bits(),
+                   INT_TIMEROVERFLOW.bits());
+        assert_eq!(cpu.reg.pc, 0x0001);
+        assert!(!cpu.ime);
+    }
+
+    #[test]
+    fn ie_push_keeping_interrupt_dispatches() {
+        let mut cpu = cpu_with_program(&[]);
+        cpu.ime = true;
+        // Pushing PC high byte 0x04 to IE keeps the timer interrupt enabled
+        cpu.reg.pc = 0x0450;
+        cpu.reg.sp = 0x0000;
+        step(&mut cpu, 1);
+        assert_eq!(cpu.interconnect.int_enable, 0x04);
+        assert_eq!(cpu.interconnect.int_flags & INT_TIMEROVERFLOW.bits(), 0);
+        assert_eq!(cpu.reg.pc, TIMER_VECTOR + 1);
+    }
+}
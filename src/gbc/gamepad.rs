@@ -1,4 +1,5 @@
 use super::Interrupts;
+use super::save_state::{StateWriter,StateReader,StateError};
 
 #[derive(Debug)]
 pub enum ButtonState {
@@ -45,6 +46,7 @@ impl InputEvent {
     }
 }
 
+#[derive(Clone)]
 pub struct Gamepad {
     input_port_1: u8,
     input_port_2: u8,
@@ -60,8 +62,7 @@ impl Gamepad {
         }
     }
 
-    pub fn read(&mut self) -> u8 {
-
+    pub fn read(&self) -> u8 {
         let mut input = self.port | 0b1100_0000;
 
         if (self.port & 0x10) != 0 {
@@ -79,6 +80,19 @@ impl Gamepad {
         self.port = val & 0b0011_0000
     }
 
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.input_port_1);
+        state.write_u8(self.input_port_2);
+        state.write_u8(self.port)
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.input_port_1 = state.read_u8()?;
+        self.input_port_2 = state.read_u8()?;
+        self.port = state.read_u8()?;
+        Ok(())
+    }
+
     pub fn cycle_flush(&mut self, _cycle_count: u32) -> Interrupts {
         Interrupts::empty()
     }
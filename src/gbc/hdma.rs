@@ -0,0 +1,132 @@
+use super::save_state::{StateWriter,StateReader,StateError};
+
+// CGB VRAM DMA, transfers data to VRAM in blocks of 16 bytes
+pub const HDMA_BLOCK_SIZE: u16 = 0x10;
+
+// The CPU is halted for 8 M-cycles per block in normal speed mode
+pub const HDMA_BLOCK_CYCLES: u32 = 32;
+
+#[derive(Debug)]
+pub enum HdmaStart {
+    // General purpose DMA, every block is transferred at once
+    General,
+    // HBlank DMA, one block is transferred at the start of every HBlank
+    HBlank,
+    // An active HBlank DMA was cancelled
+    Cancelled,
+}
+
+#[derive(Debug,Clone)]
+pub struct Hdma {
+    source: u16,
+    destination: u16,
+    remaining_blocks: u8,
+    hblank_active: bool,
+}
+
+impl Hdma {
+    pub fn new() -> Hdma {
+        Hdma {
+            source: 0,
+            destination: 0,
+            remaining_blocks: 0,
+            hblank_active: false,
+        }
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xff51..=0xff54 => 0xff,
+            0xff55 => {
+                let length = self.remaining_blocks.wrapping_sub(1) & 0x7f;
+                if self.hblank_active {
+                    length
+                } else {
+                    length | 0x80
+                }
+            }
+            _ => panic!("Address not in range 0x{:x}", addr),
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, val: u8) -> Option<HdmaStart> {
+        match addr {
+            0xff51 => self.source = (self.source & 0x00ff) | ((val as u16) << 8),
+            0xff52 => self.source = (self.source & 0xff00) | (val & 0xf0) as u16,
+            0xff53 => {
+                self.destination = (self.destination & 0x00ff) | (((val & 0x1f) as u16) << 8)
+            }
+            0xff54 => self.destination = (self.destination & 0xff00) | (val & 0xf0) as u16,
+            0xff55 => {
+                if self.hblank_active && (val & 0x80) == 0 {
+                    self.hblank_active = false;
+                    return Some(HdmaStart::Cancelled);
+                }
+
+                self.remaining_blocks = (val & 0x7f) + 1;
+
+                return if (val & 0x80) != 0 {
+                    self.hblank_active = true;
+                    Some(HdmaStart::HBlank)
+                } else {
+                    Some(HdmaStart::General)
+                };
+            }
+            _ => panic!("Address not in range 0x{:x}", addr),
+        }
+        None
+    }
+
+    // HDMA1-4 are write-only, this gives the address bits that were kept from the last writes
+    pub fn address_register(&self, addr: u16) -> u8 {
+        match addr {
+            0xff51 => (self.source >> 8) as u8,
+            0xff52 => self.source as u8,
+            0xff53 => (self.destination >> 8) as u8,
+            0xff54 => self.destination as u8,
+            _ => panic!("Address not in range 0x{:x}", addr),
+        }
+    }
+
+    pub fn hblank_active(&self) -> bool {
+        self.hblank_active
+    }
+
+    pub fn remaining_blocks(&self) -> u8 {
+        self.remaining_blocks
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.source);
+        state.write_u16(self.destination);
+        state.write_u8(self.remaining_blocks);
+        state.write_bool(self.hblank_active)
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.source = state.read_u16()?;
+        self.destination = state.read_u16()? & 0x1ff0;
+        self.remaining_blocks = state.read_u8()?;
+        self.hblank_active = state.read_bool()?;
+        Ok(())
+    }
+
+    // Returns the (source, destination) of the next block and advances the transfer.
+    // The destination is relative to the start of VRAM.
+    pub fn next_block(&mut self) -> (u16, u16) {
+        let block = (self.source, self.destination & 0x1ff0);
+
+        self.source = self.source.wrapping_add(HDMA_BLOCK_SIZE);
+        self.destination = self.destination.wrapping_add(HDMA_BLOCK_SIZE);
+        self.remaining_blocks -= 1;
+
+        // The transfer also ends when the destination wraps past the end of VRAM
+        if self.remaining_blocks == 0 || self.destination > 0x1fff {
+            self.destination &= 0x1fff;
+            self.remaining_blocks = 0;
+            self.hblank_active = false
+        }
+
+        block
+    }
+}
@@ -1,24 +1,44 @@
-use super::ppu::{Ppu,VideoSink};
+use super::ppu::Ppu;
 use super::spu::Spu;
 use super::cart::Cart;
 use super::timer::Timer;
 use super::gamepad::Gamepad;
+use super::hdma::{Hdma,HdmaStart,HDMA_BLOCK_SIZE,HDMA_BLOCK_CYCLES};
+use super::oam_dma::OamDma;
+use super::save_state::{StateWriter,StateReader,StateError};
+use super::bess::{BessState,copy_buffer};
 use super::GameboyType;
+use super::CpuClock;
+
+pub const DMG_BOOT_ROM_SIZE: usize = 0x100;
+pub const CGB_BOOT_ROM_SIZE: usize = 0x900;
 
 const ZRAM_SIZE: usize = 0x7f;
 const RAM_SIZE: usize = 1024 * 32;
+const DMG_RAM_SIZE: usize = 1024 * 8;
 
+#[derive(Clone)]
 pub struct Interconnect {
     gameboy_type: GameboyType,
+    boot_rom: Option<Box<[u8]>>,
+    // The boot ROM image is kept after 0xff50 unmaps it, so states made while it was still
+    // mapped can be loaded
+    boot_rom_mapped: bool,
+    key0: u8,
     pub cart: Cart,
-    ppu: Ppu,
-    spu: Spu,
+    pub ppu: Ppu,
+    pub spu: Spu,
     timer: Timer,
     pub gamepad: Gamepad,
     ram: Box<[u8]>,
     zram: Box<[u8]>,
     svbk: u8,
     ppu_dma: u8,
+    oam_dma: OamDma,
+    hdma: Hdma,
+    dma_stall_cycles: u32,
+    cpu_clock: CpuClock,
+    speed_switch_armed: bool,
     pub int_enable: u8,
     pub int_flags: u8,
     ram_offset: usize,
@@ -26,140 +46,483 @@ pub struct Interconnect {
 
 impl Interconnect {
     pub fn new(gameboy_type: GameboyType,
+               boot_rom: Option<Box<[u8]>>,
                cart: Cart,
                ppu: Ppu,
                spu: Spu,
                gamepad: Gamepad)
                -> Interconnect {
         Interconnect {
-            gameboy_type: gameboy_type,
-            cart: cart,
-            ppu: ppu,
-            spu: spu,
+            gameboy_type,
+            boot_rom_mapped: boot_rom.is_some(),
+            boot_rom,
+            key0: 0,
+            cart,
+            ppu,
+            spu,
             timer: Timer::new(),
-            gamepad: gamepad,
+            gamepad,
             ram: vec![0; RAM_SIZE].into_boxed_slice(),
             zram: vec![0; ZRAM_SIZE].into_boxed_slice(),
             svbk: 0,
             ppu_dma: 0,
+            oam_dma: OamDma::new(),
+            hdma: Hdma::new(),
+            dma_stall_cycles: 0,
+            cpu_clock: CpuClock::Normal,
+            speed_switch_armed: false,
             int_enable: 0,
             int_flags: 0,
             ram_offset: 0,
         }
     }
 
+    pub fn gameboy_type(&self) -> GameboyType {
+        self.gameboy_type
+    }
+
+    pub fn boot_rom_mapped(&self) -> bool {
+        self.boot_rom_mapped
+    }
+
+    // While OAM DMA runs the CPU can only reach the IO registers and HRAM. Other reads see
+    // the byte being transferred, and OAM itself is busy.
     pub fn read(&mut self, addr: u16) -> u8 {
+        if self.oam_dma.active() {
+            match addr {
+                0xfe00..=0xfeff => return 0xff,
+                0xff00..=0xffff => {}
+                _ => return self.oam_dma.value(),
+            }
+        }
+        self.read_bus(addr)
+    }
+
+    fn read_bus(&self, addr: u16) -> u8 {
         match addr {
-            0x0000...0x7fff => self.cart.read(addr),
-            0x8000...0x9fff => self.ppu.read(addr),
-            0xa000...0xbfff => self.cart.read_ram(addr),
-            0xc000...0xcfff => self.ram[(addr - 0xc000) as usize],
-            0xd000...0xdfff => self.ram[(addr - 0xc000) as usize + self.ram_offset],
-            0xe000...0xfdff => self.read(addr - 0xe000 + 0xc000),
+            0x0000..=0x7fff => {
+                match self.boot_rom {
+                    Some(ref boot_rom) if self.boot_rom_mapped &&
+                                          Interconnect::in_boot_rom(addr, boot_rom) => {
+                        boot_rom[addr as usize]
+                    }
+                    _ => self.cart.read(addr),
+                }
+            }
+            0x8000..=0x9fff => self.ppu.read(addr),
+            0xa000..=0xbfff => self.cart.read_ram(addr),
+            0xc000..=0xcfff => self.ram[(addr - 0xc000) as usize],
+            0xd000..=0xdfff => self.ram[(addr - 0xc000) as usize + self.ram_offset],
+            0xe000..=0xfdff => self.read_bus(addr - 0xe000 + 0xc000),
 
             0xff00 => self.gamepad.read(),
 
-            0xff01...0xff02 => {
+            0xff01..=0xff02 => {
                 // serial IO
                 0
             }
-            0xff04...0xff07 => self.timer.read(addr),
+            0xff04..=0xff07 => self.timer.read(addr),
 
-            0xff10...0xff3f => self.spu.read(addr),
+            0xff10..=0xff3f => self.spu.read(addr),
 
-            0xff0f => self.int_flags,
+            // The upper 3 bits of IF are unused and read as 1
+            0xff0f => self.int_flags | 0b1110_0000,
 
             0xff46 => self.ppu_dma,
 
-            0xfe00...0xfeff | 0xff40...0xff45 | 0xff47...0xff4b | 0xff68...0xff69 | 0xff4f => {
+            0xfe00..=0xfeff | 0xff40..=0xff45 | 0xff47..=0xff4b | 0xff68..=0xff6c | 0xff4f => {
                 self.ppu.read(addr)
             }
 
-            0xff4d => 0, // Speedswitch
-            0xff70 => self.svbk,
-            0xff80...0xfffe => self.zram[(addr - 0xff80) as usize],
+            0xff4c => self.key0,
+            0xff50 => 0xff,
+
+            0xff4d => self.read_key1(),
+            0xff51..=0xff55 => {
+                match self.gameboy_type {
+                    GameboyType::Cgb => self.hdma.read(addr),
+                    GameboyType::Dmg => 0xff,
+                }
+            }
+            0xff70 => {
+                match self.gameboy_type {
+                    GameboyType::Cgb => self.svbk | 0b1111_1000,
+                    GameboyType::Dmg => 0xff,
+                }
+            }
+            0xff80..=0xfffe => self.zram[(addr - 0xff80) as usize],
             0xffff => self.int_enable,
             _ => panic!("Read: addr not in range: 0x{:x}", addr),
         }
     }
 
     pub fn write(&mut self, addr: u16, val: u8) {
+        if self.oam_dma.active() && addr < 0xff00 {
+            return;
+        }
+        self.write_bus(addr, val)
+    }
+
+    fn write_bus(&mut self, addr: u16, val: u8) {
         match addr {
-            0x0000...0x7fff => self.cart.write(addr, val),
-            0x8000...0x9fff => self.ppu.write(addr, val),
-            0xa000...0xbfff => self.cart.write_ram(addr, val),
-            0xc000...0xcfff => self.ram[(addr - 0xc000) as usize] = val,
-            0xd000...0xdfff => self.ram[(addr - 0xc000) as usize + self.ram_offset] = val,
-            0xe000...0xfdff => self.write(addr - 0xe000 + 0xc000, val),
+            0x0000..=0x7fff => self.cart.write(addr, val),
+            0x8000..=0x9fff => self.ppu.write(addr, val),
+            0xa000..=0xbfff => self.cart.write_ram(addr, val),
+            0xc000..=0xcfff => self.ram[(addr - 0xc000) as usize] = val,
+            0xd000..=0xdfff => self.ram[(addr - 0xc000) as usize + self.ram_offset] = val,
+            0xe000..=0xfdff => self.write_bus(addr - 0xe000 + 0xc000, val),
 
             0xff00 => self.gamepad.write(val),
 
-            0xff01...0xff02 => {
+            0xff01..=0xff02 => {
                 // serial IO
                 if addr == 0xff01 {
                     // print!("{}", val as char)
                 }
             }
-            0xff04...0xff07 => self.timer.write(addr, val),
+            0xff04..=0xff07 => self.timer.write(addr, val),
 
-            0xff10...0xff3f => self.spu.write(addr, val),
+            0xff10..=0xff3f => self.spu.write(addr, val),
 
-            0xff0f => self.int_flags = val,
+            0xff0f => self.int_flags = val & 0b1_1111,
 
             0xff46 => {
                 self.ppu_dma = val;
-                self.ppu_dma_transfer()
+                self.oam_dma.start(val)
             }
 
-            0xfe00...0xfeff | 0xff40...0xff45 | 0xff47...0xff4b | 0xff68...0xff69 | 0xff4f => {
+            0xfe00..=0xfeff | 0xff40..=0xff45 | 0xff47..=0xff4b | 0xff68..=0xff6c | 0xff4f => {
                 self.ppu.write(addr, val)
             }
 
-            0xff4d => {} // Speedswitch
+            0xff4c => {
+                // KEY0 is written by the CGB boot ROM to select DMG compatibility mode
+                if self.gameboy_type == GameboyType::Cgb && self.boot_rom_mapped {
+                    self.key0 = val;
+                    self.ppu.set_dmg_compatibility((val & 0b100) != 0)
+                }
+            }
+            0xff50 => {
+                if val != 0 {
+                    self.boot_rom_mapped = false
+                }
+            }
+
+            0xff4d => {
+                if self.gameboy_type == GameboyType::Cgb {
+                    self.speed_switch_armed = (val & 0b1) != 0
+                }
+            }
+            0xff51..=0xff55 => {
+                if self.gameboy_type == GameboyType::Cgb {
+                    self.hdma_write(addr, val)
+                }
+            }
             0xff70 => {
-                self.svbk = val & 0b111;
-                self.update_ram_offset()
+                if self.gameboy_type == GameboyType::Cgb {
+                    self.svbk = val & 0b111;
+                    self.update_ram_offset()
+                }
             }
 
             0xff7f => {} // TETRIS writes to this address for some reason
 
-            0xff80...0xfffe => self.zram[(addr - 0xff80) as usize] = val,
+            0xff80..=0xfffe => self.zram[(addr - 0xff80) as usize] = val,
             0xffff => self.int_enable = val,
             _ => panic!("Write: addr not in range: 0x{:x} - val: 0x{:x}", addr, val),
         }
     }
 
-    pub fn cycle_flush(&mut self, cycle_count: u32, video_sink: &mut dyn VideoSink) {
+    // cycle_count is in CPU cycles. In double speed mode the PPU and APU run at half the
+    // CPU rate, while the timer follows the CPU.
+    pub fn cycle_flush(&mut self, cycle_count: u32) {
+        let video_cycles = match self.cpu_clock {
+            CpuClock::Normal => cycle_count,
+            CpuClock::Double => cycle_count / 2,
+        };
+
+        self.oam_dma_cycle_flush(cycle_count);
+
+        let ppu_ints = self.ppu.cycle_flush(video_cycles);
+
+        if self.ppu.take_hblank_started() && self.hdma.hblank_active() {
+            self.hdma_transfer_block()
+        }
+
+        self.cart.cycle_flush(video_cycles);
 
-        let ppu_ints = self.ppu.cycle_flush(cycle_count, video_sink);
         let timer_ints = self.timer.cycle_flush(cycle_count);
         let gamepad_ints = self.gamepad.cycle_flush(cycle_count);
 
+        for _ in 0..self.timer.take_apu_ticks() {
+            self.spu.clock_div_apu()
+        }
+        self.spu.cycle_flush(video_cycles);
+
         let interrupts = ppu_ints | timer_ints | gamepad_ints;
 
         self.int_flags |= interrupts.bits
     }
 
-    fn ppu_dma_transfer(&mut self) {
-        let dma_start = (self.ppu_dma as u16) << 8;
-        let dma_end = dma_start | 0x009f;
+    // Performs the speed switch if it was prepared through KEY1. Returns true if it happened.
+    pub fn speed_switch(&mut self) -> bool {
+        if !self.speed_switch_armed {
+            return false;
+        }
+
+        self.speed_switch_armed = false;
+        self.cpu_clock = match self.cpu_clock {
+            CpuClock::Normal => CpuClock::Double,
+            CpuClock::Double => CpuClock::Normal,
+        };
+        self.timer.write(0xff04, 0);
+        self.timer.set_double_speed(self.cpu_clock == CpuClock::Double);
+        true
+    }
+
+    fn read_key1(&self) -> u8 {
+        if self.gameboy_type == GameboyType::Dmg {
+            return 0xff;
+        }
+
+        let mut key1 = 0b0111_1110;
+        if self.cpu_clock == CpuClock::Double {
+            key1 |= 0b1000_0000
+        }
+        if self.speed_switch_armed {
+            key1 |= 0b0000_0001
+        }
+        key1
+    }
+
+    // Cycles the CPU is halted for by VRAM DMA transfers since the last call
+    pub fn take_dma_stall_cycles(&mut self) -> u32 {
+        let cycles = self.dma_stall_cycles;
+        self.dma_stall_cycles = 0;
+        cycles
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.boot_rom_mapped);
+        state.write_u8(self.key0);
+        state.write_bytes(&self.ram);
+        state.write_bytes(&self.zram);
+        state.write_u8(self.svbk);
+        state.write_u8(self.ppu_dma);
+        state.write_u32(self.dma_stall_cycles);
+        state.write_bool(self.cpu_clock == CpuClock::Double);
+        state.write_bool(self.speed_switch_armed);
+        state.write_u8(self.int_enable);
+        state.write_u8(self.int_flags);
+        self.timer.save_state(state);
+        self.gamepad.save_state(state);
+        self.oam_dma.save_state(state);
+        self.hdma.save_state(state);
+        self.ppu.save_state(state);
+        self.spu.save_state(state);
+        self.cart.save_state(state)
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.boot_rom_mapped = state.read_bool()?;
+        if self.boot_rom_mapped && self.boot_rom.is_none() {
+            return Err(StateError::BootRomMissing);
+        }
+        self.key0 = state.read_u8()?;
+        state.read_bytes(&mut self.ram)?;
+        state.read_bytes(&mut self.zram)?;
+        self.svbk = state.read_u8()? & 0b111;
+        self.update_ram_offset();
+        self.ppu_dma = state.read_u8()?;
+        self.dma_stall_cycles = state.read_u32()?;
+        self.cpu_clock = if state.read_bool()? {
+            CpuClock::Double
+        } else {
+            CpuClock::Normal
+        };
+        self.speed_switch_armed = state.read_bool()?;
+        self.int_enable = state.read_u8()?;
+        self.int_flags = state.read_u8()? & 0b1_1111;
+        self.timer.load_state(state)?;
+        self.gamepad.load_state(state)?;
+        self.oam_dma.load_state(state)?;
+        self.hdma.load_state(state)?;
+        self.ppu.load_state(state)?;
+        self.spu.load_state(state)?;
+        self.cart.load_state(state)
+    }
+
+    pub fn save_bess(&self, bess: &mut BessState) {
+        for (i, io) in bess.io.iter_mut().enumerate() {
+            *io = self.bess_io_register(0xff00 + i as u16)
+        }
+        bess.ie = self.int_enable;
+        bess.ram = match self.gameboy_type {
+            GameboyType::Cgb => self.ram.to_vec(),
+            GameboyType::Dmg => self.ram[..DMG_RAM_SIZE].to_vec(),
+        };
+        bess.hram = self.zram.to_vec();
+        bess.cart_ram = self.cart.copy_ram().map_or(Vec::new(), |ram| ram.into_vec());
+        bess.mbc_writes = self.cart.bank_writes();
+        bess.rtc = self.cart.rtc_data();
+        self.ppu.save_bess(bess)
+    }
+
+    // Write-only registers are exported with the values that were last written, and
+    // unmapped ones as 0xff
+    fn bess_io_register(&self, addr: u16) -> u8 {
+        match addr {
+            0xff03 | 0xff08..=0xff0e | 0xff4e | 0xff56..=0xff67 | 0xff6d..=0xff6f |
+            0xff71..=0xff7f => 0xff,
+            0xff10..=0xff3f => self.spu.register(addr),
+            0xff4c if self.gameboy_type == GameboyType::Cgb => {
+                if self.ppu.dmg_compatibility() { 0x04 } else { 0x80 }
+            }
+            // Bit 0 is set once the boot ROM has been unmapped
+            0xff50 if self.boot_rom_mapped => 0xfe,
+            0xff51..=0xff54 if self.gameboy_type == GameboyType::Cgb => {
+                self.hdma.address_register(addr)
+            }
+            _ => self.read_bus(addr),
+        }
+    }
+
+    // Only the registers and memory are restored, DMA transfers in flight are dropped
+    pub fn load_bess(&mut self, bess: &BessState) -> Result<(), StateError> {
+        let io = &bess.io;
+
+        // All checks happen before anything is changed
+        let boot_rom_mapped = io[0x50] & 0b1 == 0;
+        if boot_rom_mapped && self.boot_rom.is_none() {
+            return Err(StateError::BootRomMissing);
+        }
+        let mbc_writes: Vec<(u16, u8)> = bess.mbc_writes
+            .iter()
+            .cloned()
+            .filter(|&(addr, _)| addr < 0x8000)
+            .collect();
+        if !self.cart.bank_writes_in_range(&mbc_writes) {
+            return Err(StateError::InvalidFormat);
+        }
+        self.ppu.load_bess(bess)?;
+        self.boot_rom_mapped = boot_rom_mapped;
+
+        copy_buffer(&mut self.ram, &bess.ram);
+        copy_buffer(&mut self.zram, &bess.hram);
+        copy_buffer(self.cart.ram_mut(), &bess.cart_ram);
+        for &(addr, val) in mbc_writes.iter() {
+            self.cart.write(addr, val)
+        }
+        if let Some(ref rtc) = bess.rtc {
+            self.cart.load_rtc_data(rtc)
+        }
 
-        // if dma_start > 0x7fff && dma_end < 0xc000 {
-        // panic!("Illegal DMA address range: 0x{:x} - 0x{:x}",
-        // dma_start,
-        // dma_end);
-        // }
+        self.gamepad.write(io[0x00]);
+        self.timer.set_registers(io[0x04], io[0x05], io[0x06], io[0x07]);
+        self.int_flags = io[0x0f] & 0b1_1111;
+        self.int_enable = bess.ie;
+        self.load_bess_sound(io);
 
-        let mut oam = vec![0; super::ppu::OAM_SIZE].into_boxed_slice();
+        self.ppu_dma = io[0x46];
+        self.oam_dma = OamDma::new();
+        self.hdma = Hdma::new();
+        self.dma_stall_cycles = 0;
 
-        for a in dma_start..dma_end {
-            oam[(a - dma_start) as usize] = self.read(a)
+        if self.gameboy_type == GameboyType::Cgb {
+            self.key0 = io[0x4c];
+            self.ppu.set_dmg_compatibility((io[0x4c] & 0b100) != 0);
+            self.cpu_clock = if (io[0x4d] & 0b1000_0000) != 0 {
+                CpuClock::Double
+            } else {
+                CpuClock::Normal
+            };
+            self.timer.set_double_speed(self.cpu_clock == CpuClock::Double);
+            self.speed_switch_armed = (io[0x4d] & 0b1) != 0;
+            for addr in 0xff51..0xff55 {
+                self.hdma.write(addr, io[(addr - 0xff00) as usize]);
+            }
+            // An HBlank transfer in progress reads with bit 7 cleared, it is resumed
+            if (io[0x55] & 0b1000_0000) == 0 {
+                self.hdma.write(0xff55, io[0x55] | 0b1000_0000);
+            }
+            self.svbk = io[0x70] & 0b111;
+            self.update_ram_offset()
         }
+        Ok(())
+    }
+
+    // The APU is powered off to reset it, then the registers are written back. Channels that
+    // were playing are restarted from the beginning of their note.
+    fn load_bess_sound(&mut self, io: &[u8]) {
+        self.spu.write(0xff26, 0);
+        self.spu.write(0xff26, io[0x26]);
+        for addr in 0xff10..0xff26 {
+            let val = io[(addr - 0xff00) as usize];
+            match addr {
+                0xff14 | 0xff19 | 0xff1e | 0xff23 => self.spu.write(addr, val & 0b0111_1111),
+                _ => self.spu.write(addr, val),
+            }
+        }
+        for addr in 0xff30..0xff40 {
+            self.spu.write(addr, io[(addr - 0xff00) as usize])
+        }
+        for (channel, &addr) in [0xff14, 0xff19, 0xff1e, 0xff23].iter().enumerate() {
+            if (io[0x26] & (1 << channel)) != 0 {
+                self.spu.write(addr, io[(addr - 0xff00) as usize] | 0b1000_0000)
+            }
+        }
+    }
+
+    fn hdma_write(&mut self, addr: u16, val: u8) {
+        match self.hdma.write(addr, val) {
+            Some(HdmaStart::General) => {
+                while self.hdma.remaining_blocks() > 0 {
+                    self.hdma_transfer_block()
+                }
+            }
+            Some(HdmaStart::HBlank) => {
+                // With the LCD off there are no HBlanks, the first block is copied right away
+                if !self.ppu.lcd_enabled() {
+                    self.hdma_transfer_block()
+                }
+            }
+            Some(HdmaStart::Cancelled) | None => {}
+        }
+    }
+
+    fn hdma_transfer_block(&mut self) {
+        let (source, destination) = self.hdma.next_block();
+        for i in 0..HDMA_BLOCK_SIZE {
+            let val = self.read_bus(source.wrapping_add(i));
+            self.ppu.hdma_write(destination + i, val)
+        }
+        // The transfer takes the same amount of time in both speed modes
+        self.dma_stall_cycles += match self.cpu_clock {
+            CpuClock::Normal => HDMA_BLOCK_CYCLES,
+            CpuClock::Double => HDMA_BLOCK_CYCLES * 2,
+        }
+    }
+
+    fn oam_dma_cycle_flush(&mut self, cycle_count: u32) {
+        for _ in 0..self.oam_dma.cycle_flush(cycle_count) {
+            if let Some((source, offset)) = self.oam_dma.step() {
+                let val = self.read_bus(source);
+                self.oam_dma.set_value(val);
+                self.ppu.oam_dma_write(offset, val)
+            }
+        }
+    }
 
-        self.ppu.oam_dma_transfer(oam)
+    // The boot ROM is mapped over 0x0000-0x00ff, and on CGB also over 0x0200-0x08ff. The
+    // cart header in between stays visible.
+    fn in_boot_rom(addr: u16, boot_rom: &[u8]) -> bool {
+        let addr = addr as usize;
+        addr < DMG_BOOT_ROM_SIZE || (addr >= 0x200 && addr < boot_rom.len())
     }
 
+    // 0xd000-0xdfff maps WRAM bank 1-7, selecting bank 0 selects bank 1
     fn update_ram_offset(&mut self) {
-        self.ram_offset = self.svbk as usize * 0x1000
+        let bank = if self.svbk == 0 { 1 } else { self.svbk as usize };
+        self.ram_offset = (bank - 1) * 0x1000
     }
 }
@@ -1,7 +1,8 @@
 use super::Mbc;
 use super::MbcInfo;
+use super::super::save_state::{StateWriter,StateReader,StateError};
 
-#[derive(Debug)]
+#[derive(Debug,Clone)]
 pub struct Mbc1 {
     ram_write_protected: bool,
     rom_bank_0: u8,
@@ -96,4 +97,47 @@ impl Mbc for Mbc1 {
             None
         }
     }
+
+    fn ram_enabled(&self) -> bool {
+        !self.ram_write_protected
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.ram_write_protected);
+        state.write_u8(self.rom_bank_0);
+        state.write_u8(self.rom_bank_1);
+        state.write_u8(self.ram_select);
+        state.write_bytes(&self.ram)
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.ram_write_protected = state.read_bool()?;
+        self.rom_bank_0 = state.read_u8()?;
+        self.rom_bank_1 = state.read_u8()?;
+        self.ram_select = state.read_u8()?;
+        state.read_bytes(&mut self.ram)?;
+        self.update_rom_offset();
+        self.update_ram_offset();
+        Ok(())
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn box_clone(&self) -> Box<dyn Mbc> {
+        Box::new(self.clone())
+    }
+
+    fn banks_in_range(&self, rom_size: usize) -> bool {
+        self.rom_offset + 0x4000 <= rom_size &&
+        (self.ram_offset == 0 || self.ram_offset < self.ram.len())
+    }
+
+    fn bank_writes(&self) -> Vec<(u16, u8)> {
+        vec![(0x0000, if self.ram_write_protected { 0x00 } else { 0x0a }),
+             (0x2000, self.rom_bank_0),
+             (0x4000, self.rom_bank_1),
+             (0x6000, self.ram_select)]
+    }
 }
@@ -1,5 +1,15 @@
 use super::Mbc;
 use super::MbcInfo;
+use super::{RTC_DATA_SIZE,unix_time};
+use super::super::save_state::{StateWriter,StateReader,StateError};
+use super::super::CpuClock;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+// Bits of the upper day counter register
+const DAYS_HIGH_BIT: u8 = 0b0000_0001;
+const HALT_BIT: u8 = 0b0100_0000;
+const DAY_CARRY_BIT: u8 = 0b1000_0000;
 
 #[derive(Debug,Copy,Clone)]
 struct Rtc {
@@ -10,19 +20,105 @@ struct Rtc {
     rtc_days_high: u8,
 }
 
-#[derive(Debug)]
+#[derive(Debug,Clone)]
 pub struct Mbc3 {
     ram_write_protected: bool,
     rom_bank: u8,
     ram_bank: u8,
     rtc_latch: u8,
+    has_rtc: bool,
     rtc: Rtc,
     latched_rtc: Rtc,
+    // Cycles into the current RTC second
+    rtc_cycles: u32,
     rom_offset: usize,
     ram_offset: usize,
     ram: Box<[u8]>,
 }
 
+impl Rtc {
+    fn registers(&self) -> [u8; 5] {
+        [self.rtc_seconds, self.rtc_minutes, self.rtc_hours, self.rtc_days_low, self.rtc_days_high]
+    }
+
+    fn set_registers(&mut self, registers: &[u8]) {
+        self.rtc_seconds = registers[0] & 0x3f;
+        self.rtc_minutes = registers[1] & 0x3f;
+        self.rtc_hours = registers[2] & 0x1f;
+        self.rtc_days_low = registers[3];
+        self.rtc_days_high = registers[4] & 0b1100_0001
+    }
+
+    fn halted(&self) -> bool {
+        (self.rtc_days_high & HALT_BIT) != 0
+    }
+
+    fn days(&self) -> u16 {
+        (((self.rtc_days_high & DAYS_HIGH_BIT) as u16) << 8) | self.rtc_days_low as u16
+    }
+
+    // The day counter is 9 bits, the carry flag stays set until it is cleared by a write
+    fn add_days(&mut self, days: u64) {
+        let days = self.days() as u64 + days;
+        if days > 0x1ff {
+            self.rtc_days_high |= DAY_CARRY_BIT
+        }
+        self.rtc_days_low = days as u8;
+        self.rtc_days_high = (self.rtc_days_high & !DAYS_HIGH_BIT) | ((days >> 8) as u8 & DAYS_HIGH_BIT)
+    }
+
+    // Counters that were set out of range count up to the limit of their bits and wrap to 0
+    // without carrying into the next one
+    fn tick(&mut self) {
+        self.rtc_seconds = (self.rtc_seconds + 1) & 0x3f;
+        if self.rtc_seconds != 60 {
+            return;
+        }
+        self.rtc_seconds = 0;
+
+        self.rtc_minutes = (self.rtc_minutes + 1) & 0x3f;
+        if self.rtc_minutes != 60 {
+            return;
+        }
+        self.rtc_minutes = 0;
+
+        self.rtc_hours = (self.rtc_hours + 1) & 0x1f;
+        if self.rtc_hours != 24 {
+            return;
+        }
+        self.rtc_hours = 0;
+
+        self.add_days(1)
+    }
+
+    fn advance(&mut self, seconds: u64) {
+        if self.halted() {
+            return;
+        }
+        for _ in 0..seconds % SECONDS_PER_DAY {
+            self.tick()
+        }
+        self.add_days(seconds / SECONDS_PER_DAY)
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.rtc_seconds);
+        state.write_u8(self.rtc_minutes);
+        state.write_u8(self.rtc_hours);
+        state.write_u8(self.rtc_days_low);
+        state.write_u8(self.rtc_days_high)
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.rtc_seconds = state.read_u8()?;
+        self.rtc_minutes = state.read_u8()?;
+        self.rtc_hours = state.read_u8()?;
+        self.rtc_days_low = state.read_u8()?;
+        self.rtc_days_high = state.read_u8()?;
+        Ok(())
+    }
+}
+
 impl Mbc3 {
     pub fn new(mbc_info: MbcInfo, ram: Option<Box<[u8]>>) -> Mbc3 {
         let ram = if let Some(ram_info) = mbc_info.ram_info {
@@ -42,11 +138,13 @@ impl Mbc3 {
             rom_bank: 0,
             ram_bank: 0,
             rtc_latch: 0,
-            rtc: rtc,
+            has_rtc: mbc_info.has_rtc,
+            rtc,
             latched_rtc: rtc,
+            rtc_cycles: 0,
             rom_offset: 0,
             ram_offset: 0,
-            ram: ram,
+            ram,
         }
     }
 
@@ -92,7 +190,7 @@ impl Mbc for Mbc3 {
 
     fn read_ram(&self, addr: u16) -> u8 {
         match self.ram_bank {
-            0...3 => self.ram[addr as usize - 0xa000 + self.ram_offset],
+            0..=3 => self.ram[addr as usize - 0xa000 + self.ram_offset],
             0x08 => self.latched_rtc.rtc_seconds,
             0x09 => self.latched_rtc.rtc_minutes,
             0x0a => self.latched_rtc.rtc_hours,
@@ -105,8 +203,12 @@ impl Mbc for Mbc3 {
     fn write_ram(&mut self, addr: u16, val: u8) {
         if !self.ram_write_protected {
             match self.ram_bank {
-                0...3 => self.ram[addr as usize - 0xa000 + self.ram_offset] = val,
-                0x08 => self.rtc.rtc_seconds = val & 0x3f,
+                0..=3 => self.ram[addr as usize - 0xa000 + self.ram_offset] = val,
+                // Writing the seconds also restarts the current second
+                0x08 => {
+                    self.rtc.rtc_seconds = val & 0x3f;
+                    self.rtc_cycles = 0
+                }
                 0x09 => self.rtc.rtc_minutes = val & 0x3f,
                 0x0a => self.rtc.rtc_hours = val & 0x1f,
                 0x0b => self.rtc.rtc_days_low = val,
@@ -123,4 +225,99 @@ impl Mbc for Mbc3 {
             None
         }
     }
+
+    fn ram_enabled(&self) -> bool {
+        !self.ram_write_protected
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.ram_write_protected);
+        state.write_u8(self.rom_bank);
+        state.write_u8(self.ram_bank);
+        state.write_u8(self.rtc_latch);
+        self.rtc.save_state(state);
+        self.latched_rtc.save_state(state);
+        state.write_u32(self.rtc_cycles);
+        state.write_bytes(&self.ram)
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.ram_write_protected = state.read_bool()?;
+        self.rom_bank = state.read_u8()?;
+        self.ram_bank = state.read_u8()?;
+        self.rtc_latch = state.read_u8()?;
+        self.rtc.load_state(state)?;
+        self.latched_rtc.load_state(state)?;
+        self.rtc_cycles = state.read_u32()? % CpuClock::Normal.value();
+        state.read_bytes(&mut self.ram)?;
+        self.update_rom_offset();
+        self.update_ram_offset();
+        Ok(())
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn box_clone(&self) -> Box<dyn Mbc> {
+        Box::new(self.clone())
+    }
+
+    // Banks 0x08-0x0c select the RTC registers instead of RAM
+    fn banks_in_range(&self, rom_size: usize) -> bool {
+        let ram_in_range = match self.ram_bank {
+            0..=3 => self.ram_offset == 0 || self.ram_offset < self.ram.len(),
+            0x08..=0x0c => true,
+            _ => false,
+        };
+        self.rom_offset + 0x4000 <= rom_size && ram_in_range
+    }
+
+    fn bank_writes(&self) -> Vec<(u16, u8)> {
+        vec![(0x0000, if self.ram_write_protected { 0x00 } else { 0x0a }),
+             (0x2000, self.rom_bank),
+             (0x4000, self.ram_bank),
+             (0x6000, self.rtc_latch)]
+    }
+
+    // The RTC has its own crystal, cycle_count is at normal speed in both CPU speed modes
+    fn cycle_flush(&mut self, cycle_count: u32) {
+        if !self.has_rtc || self.rtc.halted() {
+            return;
+        }
+        let second = CpuClock::Normal.value();
+        self.rtc_cycles += cycle_count;
+        while self.rtc_cycles >= second {
+            self.rtc_cycles -= second;
+            self.rtc.tick()
+        }
+    }
+
+    fn rtc_data(&self) -> Option<[u8; RTC_DATA_SIZE]> {
+        if !self.has_rtc {
+            return None;
+        }
+        let mut data = [0; RTC_DATA_SIZE];
+        let (rtc, latched_rtc) = (self.rtc.registers(), self.latched_rtc.registers());
+        for (i, &val) in rtc.iter().chain(latched_rtc.iter()).enumerate() {
+            data[i * 4] = val
+        }
+        let timestamp = unix_time();
+        for i in 0..8 {
+            data[40 + i] = (timestamp >> (i * 8)) as u8
+        }
+        Some(data)
+    }
+
+    fn load_rtc_data(&mut self, data: &[u8; RTC_DATA_SIZE]) {
+        let registers: Vec<u8> = data[..40].chunks(4).map(|val| val[0]).collect();
+        self.rtc.set_registers(&registers[..5]);
+        self.latched_rtc.set_registers(&registers[5..])
+    }
+
+    fn advance_rtc(&mut self, seconds: u64) {
+        if self.has_rtc {
+            self.rtc.advance(seconds)
+        }
+    }
 }
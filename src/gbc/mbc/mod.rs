@@ -6,6 +6,22 @@ use self::mbc1::Mbc1;
 use self::mbc3::Mbc3;
 use self::mbc5::Mbc5;
 
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::save_state::{StateWriter,StateReader,StateError};
+
+// The RTC as saved by VBA and BGB: the current and latched registers as 32-bit values,
+// followed by a 64-bit UNIX timestamp
+pub const RTC_DATA_SIZE: usize = 0x30;
+
+// Host time in seconds, as stored with the RTC
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0)
+}
+
 #[derive(Debug,Copy,Clone)]
 pub struct RamInfo {
     size: u32,
@@ -41,16 +57,31 @@ pub struct MbcInfo {
     mbc_type: MbcType,
     ram_info: Option<RamInfo>,
     has_batt: bool,
+    has_rtc: bool,
 }
 
 impl MbcInfo {
-    pub fn new(mbc_type: MbcType, ram_info: Option<RamInfo>, has_batt: bool) -> MbcInfo {
+    pub fn new(mbc_type: MbcType,
+               ram_info: Option<RamInfo>,
+               has_batt: bool,
+               has_rtc: bool)
+               -> MbcInfo {
         MbcInfo {
-            mbc_type: mbc_type,
-            ram_info: ram_info,
-            has_batt: has_batt,
+            mbc_type,
+            ram_info,
+            has_batt,
+            has_rtc,
         }
     }
+
+    // Only battery backed RAM keeps its contents when the console is off
+    pub fn has_batt(&self) -> bool {
+        self.has_batt
+    }
+
+    pub fn has_rtc(&self) -> bool {
+        self.has_rtc
+    }
 }
 
 #[derive(Debug)]
@@ -68,6 +99,42 @@ pub trait Mbc {
     fn read_ram(&self, addr: u16) -> u8;
     fn write_ram(&mut self, addr: u16, val: u8);
     fn copy_ram(&self) -> Option<Box<[u8]>>;
+    // Whether RAM is enabled through the 0x0000-0x1fff register
+    fn ram_enabled(&self) -> bool;
+    // Bank registers and RAM, cart RAM keeps its size so it is stored without a length
+    fn save_state(&self, state: &mut StateWriter);
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError>;
+    fn ram_mut(&mut self) -> &mut [u8];
+    fn box_clone(&self) -> Box<dyn Mbc>;
+    // Register writes that put a freshly reset MBC in the current banking state
+    fn bank_writes(&self) -> Vec<(u16, u8)>;
+    // Whether the selected ROM and RAM banks exist on the cart. Banks set by a state, or
+    // replayed from another emulator's state, are checked before they are used.
+    #[allow(unused_variables)]
+    fn banks_in_range(&self, rom_size: usize) -> bool {
+        true
+    }
+
+    // Only carts with a clock have RTC data
+    #[allow(unused_variables)]
+    fn cycle_flush(&mut self, cycle_count: u32) {}
+
+    fn rtc_data(&self) -> Option<[u8; RTC_DATA_SIZE]> {
+        None
+    }
+
+    #[allow(unused_variables)]
+    fn load_rtc_data(&mut self, data: &[u8; RTC_DATA_SIZE]) {}
+
+    // Moves the clock forward, for the time that passed while the emulator was not running
+    #[allow(unused_variables)]
+    fn advance_rtc(&mut self, seconds: u64) {}
+}
+
+impl Clone for Box<dyn Mbc> {
+    fn clone(&self) -> Box<dyn Mbc> {
+        self.box_clone()
+    }
 }
 
 pub fn new_mbc(mbc_info: MbcInfo, ram: Option<Box<[u8]>>) -> Box<Mbc> {
@@ -101,4 +168,28 @@ impl Mbc for RomOnly {
     fn copy_ram(&self) -> Option<Box<[u8]>> {
         None
     }
+
+    fn ram_enabled(&self) -> bool {
+        false
+    }
+
+    #[allow(unused_variables)]
+    fn save_state(&self, state: &mut StateWriter) {}
+
+    #[allow(unused_variables)]
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        Ok(())
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut []
+    }
+
+    fn box_clone(&self) -> Box<dyn Mbc> {
+        Box::new(RomOnly)
+    }
+
+    fn bank_writes(&self) -> Vec<(u16, u8)> {
+        Vec::new()
+    }
 }
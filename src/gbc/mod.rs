@@ -11,22 +11,26 @@ mod registers;
 mod opcode;
 mod timer;
 mod mbc;
+mod hdma;
+mod oam_dma;
+mod palette;
+mod pixel_fifo;
+mod save_state;
+mod bess;
 
-#[derive(Debug,Copy,Clone)]
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
 pub enum GameboyType {
     Cgb,
     Dmg,
 }
 
-#[allow(dead_code)]
-#[derive(Debug,Copy,Clone)]
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
 pub enum CpuClock {
     Normal,
     Double,
 }
 
 impl CpuClock {
-    #[allow(dead_code)]
     pub fn value(self) -> u32 {
         match self {
             CpuClock::Normal => 4_194_304,
@@ -0,0 +1,118 @@
+use super::save_state::{StateWriter,StateReader,StateError};
+
+// OAM DMA, copies 0xa0 bytes to OAM, one byte per M-cycle
+pub const OAM_DMA_LENGTH: u16 = 0xa0;
+
+// Length of an M-cycle in CPU cycles, the transfer takes as many M-cycles in both speed modes
+const OAM_DMA_BYTE_CYCLES: u32 = 4;
+
+#[derive(Debug,Clone)]
+pub struct OamDma {
+    source: u16,
+    index: u16,
+    active: bool,
+    // A requested transfer starts after one M-cycle, a running transfer continues until then
+    pending_source: Option<u16>,
+    cycles: u32,
+    // The byte on the bus, seen by CPU reads that conflict with the transfer
+    value: u8,
+}
+
+impl OamDma {
+    pub fn new() -> OamDma {
+        OamDma {
+            source: 0,
+            index: 0,
+            active: false,
+            pending_source: None,
+            cycles: 0,
+            value: 0xff,
+        }
+    }
+
+    // Sources above 0xdfff read from the WRAM echo
+    pub fn start(&mut self, val: u8) {
+        let source = (val as u16) << 8;
+        let source = if source >= 0xe000 {
+            source - 0x2000
+        } else {
+            source
+        };
+        self.pending_source = Some(source)
+    }
+
+    pub fn active(&self) -> bool {
+        self.active
+    }
+
+    pub fn value(&self) -> u8 {
+        self.value
+    }
+
+    pub fn set_value(&mut self, value: u8) {
+        self.value = value
+    }
+
+    // Returns the number of M-cycles that have passed
+    pub fn cycle_flush(&mut self, cycle_count: u32) -> u32 {
+        if !self.active && self.pending_source.is_none() {
+            return 0;
+        }
+        self.cycles += cycle_count;
+        let m_cycles = self.cycles / OAM_DMA_BYTE_CYCLES;
+        self.cycles %= OAM_DMA_BYTE_CYCLES;
+        m_cycles
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.source);
+        state.write_u16(self.index);
+        state.write_bool(self.active);
+        state.write_bool(self.pending_source.is_some());
+        state.write_u16(self.pending_source.unwrap_or(0));
+        state.write_u32(self.cycles);
+        state.write_u8(self.value)
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.source = state.read_u16()?;
+        self.index = state.read_u16()?;
+        self.active = state.read_bool()?;
+        let pending = state.read_bool()?;
+        let pending_source = state.read_u16()?;
+        self.pending_source = if pending { Some(pending_source) } else { None };
+        self.cycles = state.read_u32()?;
+        self.value = state.read_u8()?;
+        if self.index > OAM_DMA_LENGTH || self.source > 0xdf00 {
+            return Err(StateError::InvalidFormat);
+        }
+        Ok(())
+    }
+
+    // Advances the transfer by one M-cycle. Returns the source address and OAM offset of the
+    // byte to copy, if any.
+    pub fn step(&mut self) -> Option<(u16, u16)> {
+        let transfer = if self.active {
+            let transfer = (self.source + self.index, self.index);
+            self.index += 1;
+            if self.index == OAM_DMA_LENGTH {
+                self.active = false
+            }
+            Some(transfer)
+        } else {
+            None
+        };
+
+        if let Some(source) = self.pending_source.take() {
+            self.source = source;
+            self.index = 0;
+            self.active = true
+        }
+
+        if !self.active && self.pending_source.is_none() {
+            self.cycles = 0
+        }
+
+        transfer
+    }
+}
@@ -0,0 +1,256 @@
+use super::cart::Cart;
+
+// Colors used when rendering DMG graphics, as 0xRRGGBB for each of the 4 shades. The
+// background and the two object palettes can be colored separately.
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub struct Palette {
+    pub bg: [u32; 4],
+    pub obj_0: [u32; 4],
+    pub obj_1: [u32; 4],
+}
+
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum PalettePreset {
+    DmgGreen,
+    PocketGray,
+    // The palette the CGB boot ROM picks for the cart, from its title checksum
+    CgbColorization,
+}
+
+impl PalettePreset {
+    pub fn from_name(name: &str) -> Option<PalettePreset> {
+        match name {
+            "green" => Some(PalettePreset::DmgGreen),
+            "gray" => Some(PalettePreset::PocketGray),
+            "cgb" => Some(PalettePreset::CgbColorization),
+            _ => None,
+        }
+    }
+
+    pub fn next(self) -> PalettePreset {
+        match self {
+            PalettePreset::DmgGreen => PalettePreset::PocketGray,
+            PalettePreset::PocketGray => PalettePreset::CgbColorization,
+            PalettePreset::CgbColorization => PalettePreset::DmgGreen,
+        }
+    }
+
+    pub fn palette(self, cart: &Cart) -> Palette {
+        match self {
+            PalettePreset::DmgGreen => Palette::dmg_green(),
+            PalettePreset::PocketGray => Palette::pocket_gray(),
+            PalettePreset::CgbColorization => Palette::cgb_colorization(cart),
+        }
+    }
+}
+
+const DMG_GREEN: [u32; 4] = [0xe0f8d0, 0x88c070, 0x275046, 0x081820];
+const POCKET_GRAY: [u32; 4] = [0xc4cfa1, 0x8b956d, 0x4d533c, 0x1f1f1f];
+
+// The 30 palettes of the CGB boot ROM, as 0xRRGGBB
+const CGB_COLORS: [u32; 120] = [
+    0xffffff, 0xffad63, 0x843100, 0x000000,
+    0xffe6c5, 0xce9c84, 0x846b29, 0x5a3108,
+    0xffffff, 0x8c8cde, 0x52528c, 0x000000,
+    0xffffff, 0x7bff31, 0x008400, 0x000000,
+    0xffffff, 0xff8484, 0x943a3a, 0x000000,
+    0xffffff, 0xa5a5a5, 0x525252, 0x000000,
+    0xffffff, 0xffff00, 0x7b4a00, 0x000000,
+    0xffffff, 0x7bff00, 0xb57300, 0x000000,
+    0xffffff, 0xadad84, 0x42737b, 0x000000,
+    0xa59cff, 0xffff00, 0x006300, 0x000000,
+    0xffffce, 0x63efef, 0x9c8431, 0x5a5a5a,
+    0xb5b5ff, 0xffff94, 0xad5a42, 0x000000,
+    0xffffa5, 0xff9494, 0x9494ff, 0x000000,
+    0xffff9c, 0x94b5ff, 0x639473, 0x003a3a,
+    0x6bff00, 0xffffff, 0xff524a, 0x000000,
+    0x52de00, 0xff8400, 0xffff00, 0xffffff,
+    0xffffff, 0xff7300, 0x944200, 0x000000,
+    0xffc542, 0xffd600, 0x943a00, 0x4a0000,
+    0xffffff, 0x52ff00, 0xff4200, 0x000000,
+    0xff6352, 0xd60000, 0x630000, 0x000000,
+    0xffffff, 0xff9c00, 0xff0000, 0x000000,
+    0xffffff, 0x00ff00, 0x318400, 0x004a00,
+    0xffffff, 0x5abdff, 0xff0000, 0x0000ff,
+    0xffffff, 0xffff7b, 0x0084ff, 0xff0000,
+    0xffffff, 0xffff00, 0xff0000, 0x000000,
+    0xffff00, 0xff0000, 0x630000, 0x000000,
+    0xffffff, 0xffce00, 0x9c6300, 0x000000,
+    0x000000, 0x008484, 0xffde00, 0xffffff,
+    0xffffff, 0x63a5ff, 0x0000ff, 0x000000,
+    0xffffff, 0x7bff31, 0x0063c5, 0x000000,
+];
+
+// The OBJ0, OBJ1 and BG colors the boot ROM combines into a palette, as offsets into
+// CGB_COLORS. A few of them start in the middle of a palette.
+const CGB_PALETTE_COMBINATIONS: [(usize, usize, usize); 51] = [
+    (16, 16, 116), (72, 72, 72), (80, 80, 80), (96, 96, 96), (36, 36, 36), (0, 0, 0),
+    (108, 108, 108), (20, 20, 20), (48, 48, 48), (104, 104, 104), (64, 32, 32), (16, 112, 112),
+    (16, 8, 8), (12, 16, 16), (16, 116, 116), (112, 16, 112), (8, 68, 8), (64, 64, 32),
+    (16, 16, 28), (16, 16, 72), (16, 16, 80), (76, 76, 36), (15, 15, 44), (68, 68, 8), (16, 16, 8),
+    (16, 16, 12), (112, 112, 0), (12, 12, 0), (0, 0, 4), (72, 88, 72), (80, 88, 80), (96, 88, 96),
+    (64, 88, 32), (68, 16, 52), (111, 0, 56), (111, 16, 60), (76, 88, 36), (64, 112, 40),
+    (16, 92, 112), (68, 88, 8), (16, 0, 8), (16, 112, 12), (112, 12, 0), (12, 112, 16),
+    (84, 112, 16), (12, 112, 0), (100, 12, 112), (0, 112, 32), (16, 12, 112), (112, 12, 24),
+    (16, 112, 116),
+];
+
+// Title checksums the CGB boot ROM recognizes, with the 4th title letter for checksums that
+// are shared by several titles, and the palette combination used for the title
+const CGB_TITLE_PALETTES: [(u8, Option<u8>, usize); 93] = [
+    (0x88, None, 4), // ALLEY WAY
+    (0x16, None, 5), // YAKUMAN
+    (0x36, None, 35), // BASEBALL
+    (0xd1, None, 34), // TENNIS
+    (0xdb, None, 3), // TETRIS
+    (0xf2, None, 31), // QIX
+    (0x3c, None, 15), // DR.MARIO
+    (0x8c, None, 10), // RADARMISSION
+    (0x92, None, 5), // F1RACE
+    (0x3d, None, 19), // YOSSY NO TAMAGO
+    (0x5c, None, 36),
+    (0x58, None, 7), // X
+    (0xc9, None, 37), // MARIOLAND2
+    (0x3e, None, 30), // YOSSY NO COOKIE
+    (0x70, None, 44), // ZELDA
+    (0x1d, None, 21),
+    (0x59, None, 32),
+    (0x69, None, 31), // TETRIS FLASH
+    (0x19, None, 20), // DONKEY KONG
+    (0x35, None, 5), // MARIO'S PICROSS
+    (0xa8, None, 33),
+    (0x14, None, 13), // POKEMON RED
+    (0xaa, None, 14), // POKEMON GREEN
+    (0x75, None, 5), // PICROSS 2
+    (0x95, None, 29), // YOSSY NO PANEPON
+    (0x99, None, 5), // KIRAKIRA KIDS
+    (0x34, None, 18), // GAMEBOY GALLERY
+    (0x6f, None, 9), // POCKETCAMERA
+    (0x15, None, 3),
+    (0xff, None, 2), // BALLOON KID
+    (0x97, None, 26), // KINGOFTHEZOO
+    (0x4b, None, 25), // DMG FOOTBALL
+    (0x90, None, 25), // WORLD CUP
+    (0x17, None, 41), // OTHELLO
+    (0x10, None, 42), // SUPER RC PRO-AM
+    (0x39, None, 26), // DYNABLASTER
+    (0xf7, None, 45), // BOY AND HIS BLOB
+    (0xf6, None, 42), // MEGAMAN
+    (0xa2, None, 45), // STAR WARS-NOA
+    (0x49, None, 36),
+    (0x4e, None, 38), // WAVERACE
+    (0x43, None, 26),
+    (0x68, None, 42), // LOLO2
+    (0xe0, None, 30), // YOSHI'S COOKIE
+    (0x8b, None, 41), // MYSTIC QUEST
+    (0xf0, None, 34),
+    (0xce, None, 34), // TOPRANKINGTENNIS
+    (0x0c, None, 5), // MANSELL
+    (0x29, None, 42), // MEGAMAN3
+    (0xe8, None, 6), // SPACE INVADERS
+    (0xb7, None, 5), // GAME&WATCH
+    (0x86, None, 33), // DONKEYKONGLAND95
+    (0x9a, None, 25), // ASTEROIDS/MISCMD
+    (0x52, None, 42), // STREET FIGHTER 2
+    (0x01, None, 42), // DEFENDER/JOUST
+    (0x9d, None, 40), // KILLERINSTINCT95
+    (0x71, None, 2), // TETRIS BLAST
+    (0x9c, None, 16), // PINOCCHIO
+    (0xbd, None, 25),
+    (0x5d, None, 42), // BA.TOSHINDEN
+    (0x6d, None, 42), // NETTOU KOF 95
+    (0x67, None, 5),
+    (0x3f, None, 0), // TETRIS PLUS
+    (0x6b, None, 39), // DONKEYKONGLAND 3
+    (0xb3, Some(b'B'), 36),
+    (0x46, Some(b'E'), 22), // SUPERMARIOLAND3
+    (0x28, Some(b'F'), 25), // GOLF
+    (0xa5, Some(b'A'), 6), // SOLARSTRIKER
+    (0xc6, Some(b'A'), 32), // GBWARS
+    (0xd3, Some(b'R'), 12), // KAERUNOTAMENI
+    (0x27, Some(b'B'), 36),
+    (0x61, Some(b'E'), 11), // POKEMON BLUE
+    (0x18, Some(b'K'), 39), // DONKEYKONGLAND
+    (0x66, Some(b'E'), 18), // GAMEBOY GALLERY2
+    (0x6a, Some(b'K'), 39), // DONKEYKONGLAND 2
+    (0xbf, Some(b' '), 24), // KID ICARUS
+    (0x0d, Some(b'R'), 31), // TETRIS2
+    (0xf4, Some(b'-'), 50),
+    (0xb3, Some(b'U'), 17), // MOGURANYA
+    (0x46, Some(b'R'), 46),
+    (0x28, Some(b'A'), 6), // GALAGA&GALAXIAN
+    (0xa5, Some(b'R'), 27), // BT2RAGNAROKWORLD
+    (0xc6, Some(b' '), 0), // KEN GRIFFEY JR
+    (0xd3, Some(b'I'), 47),
+    (0x27, Some(b'N'), 41), // MAGNETIC SOCCER
+    (0x61, Some(b'A'), 41), // VEGAS STAKES
+    (0x18, Some(b'I'), 0),
+    (0x66, Some(b'L'), 0), // MILLI/CENTI/PEDE
+    (0x6a, Some(b'I'), 19), // MARIO & YOSHI
+    (0xbf, Some(b'C'), 34), // SOCCER
+    (0x0d, Some(b'E'), 23), // POKEBOM
+    (0xf4, Some(b' '), 18), // G&W GALLERY
+    (0xb3, Some(b'R'), 29), // TETRIS ATTACK
+];
+
+impl Palette {
+    pub fn new(bg: [u32; 4], obj_0: [u32; 4], obj_1: [u32; 4]) -> Palette {
+        Palette {
+            bg,
+            obj_0,
+            obj_1,
+        }
+    }
+
+    pub fn monochrome(colors: [u32; 4]) -> Palette {
+        Palette::new(colors, colors, colors)
+    }
+
+    pub fn dmg_green() -> Palette {
+        Palette::monochrome(DMG_GREEN)
+    }
+
+    pub fn pocket_gray() -> Palette {
+        Palette::monochrome(POCKET_GRAY)
+    }
+
+    // Looks up the palette the CGB boot ROM would colorize the cart with. Only carts licensed
+    // by Nintendo are colorized.
+    pub fn cgb_colorization(cart: &Cart) -> Palette {
+        // Carts without a known title get the first combination
+        let combination = if cart.is_nintendo_licensee() {
+            let checksum = cart.title_checksum();
+            let fourth_letter = cart.title_fourth_letter();
+            CGB_TITLE_PALETTES.iter()
+                .find(|&&(c, letter, _)| {
+                    c == checksum && letter.is_none_or(|l| l == fourth_letter)
+                })
+                .map_or(0, |&(_, _, combination)| combination)
+        } else {
+            0
+        };
+
+        let (obj_0, obj_1, bg) = CGB_PALETTE_COMBINATIONS[combination];
+        Palette::new(Palette::cgb_colors(bg),
+                     Palette::cgb_colors(obj_0),
+                     Palette::cgb_colors(obj_1))
+    }
+
+    fn cgb_colors(offset: usize) -> [u32; 4] {
+        let mut colors = [0; 4];
+        colors.copy_from_slice(&CGB_COLORS[offset..offset + 4]);
+        colors
+    }
+
+    // Colors converted to the 15-bit RGB format of CGB palette memory
+    pub fn to_rgb15(colors: &[u32; 4]) -> [u16; 4] {
+        let mut rgb15 = [0; 4];
+        for (i, color) in colors.iter().enumerate() {
+            let r = ((color >> 19) & 0x1f) as u16;
+            let g = ((color >> 11) & 0x1f) as u16;
+            let b = ((color >> 3) & 0x1f) as u16;
+            rgb15[i] = r | (g << 5) | (b << 10)
+        }
+        rgb15
+    }
+}
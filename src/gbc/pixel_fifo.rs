@@ -0,0 +1,259 @@
+use std::collections::VecDeque;
+
+use super::save_state::{StateWriter,StateReader,StateError};
+
+// Dots spent on the tile fetch at the start of every line, the fetched pixels are thrown away
+const LINE_START_DELAY: u8 = 6;
+
+// Dots the background fetcher and pixel output are stalled while a sprite is fetched
+pub const SPRITE_FETCH_DOTS: u8 = 6;
+
+// Sprites the OAM scan picks for each line
+pub const MAX_SPRITES_PER_LINE: usize = 10;
+
+const OAM_SPRITES: usize = 40;
+
+#[derive(Debug,Clone,Copy)]
+pub struct BgPixel {
+    pub color: u8,
+    // CGB tile attributes, 0 on DMG
+    pub attributes: u8,
+}
+
+#[derive(Debug,Clone,Copy)]
+pub struct ObjPixel {
+    pub color: u8,
+    pub attributes: u8,
+    pub oam_index: u8,
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum FetcherStep {
+    TileNumber,
+    DataLow,
+    DataHigh,
+    Push,
+}
+
+impl FetcherStep {
+    fn next(self) -> FetcherStep {
+        match self {
+            FetcherStep::TileNumber => FetcherStep::DataLow,
+            FetcherStep::DataLow => FetcherStep::DataHigh,
+            FetcherStep::DataHigh => FetcherStep::Push,
+            FetcherStep::Push => FetcherStep::TileNumber,
+        }
+    }
+}
+
+// State of the dot based renderer during pixel transfer. A fetcher fills the background FIFO
+// one tile row at a time, while one pixel per dot is shifted out to the LCD and mixed with
+// the sprite FIFO.
+#[derive(Clone)]
+pub struct PixelFifo {
+    pub active: bool,
+    pub lcd_x: u8,
+    pub discard: u8,
+    pub delay: u8,
+    pub window: bool,
+    pub bg: VecDeque<BgPixel>,
+    pub obj: VecDeque<ObjPixel>,
+    pub step: FetcherStep,
+    pub step_dots: u8,
+    pub fetcher_x: u8,
+    pub tile_line: u16,
+    pub tile_num: u8,
+    pub tile_attributes: u8,
+    pub data_low: u8,
+    pub data_high: u8,
+    // OAM indices of the sprites on this line that have not been fetched yet
+    pub sprites: Vec<u8>,
+    pub fetched_sprites: usize,
+    pub sprite_stall: u8,
+    pub pending_sprite: u8,
+}
+
+impl PixelFifo {
+    pub fn new() -> PixelFifo {
+        PixelFifo {
+            active: false,
+            lcd_x: 0,
+            discard: 0,
+            delay: 0,
+            window: false,
+            bg: VecDeque::with_capacity(16),
+            obj: VecDeque::with_capacity(8),
+            step: FetcherStep::TileNumber,
+            step_dots: 0,
+            fetcher_x: 0,
+            tile_line: 0,
+            tile_num: 0,
+            tile_attributes: 0,
+            data_low: 0,
+            data_high: 0,
+            sprites: Vec::with_capacity(MAX_SPRITES_PER_LINE),
+            fetched_sprites: 0,
+            sprite_stall: 0,
+            pending_sprite: 0,
+        }
+    }
+
+    // Starts pixel transfer for a new line, the first scx % 8 pixels are scrolled out
+    pub fn start_line(&mut self, scx: u8) {
+        self.active = true;
+        self.lcd_x = 0;
+        self.discard = scx & 0b111;
+        self.delay = LINE_START_DELAY;
+        self.window = false;
+        self.bg.clear();
+        self.obj.clear();
+        self.reset_fetcher();
+        self.sprites.clear();
+        self.fetched_sprites = 0;
+        self.sprite_stall = 0
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.active);
+        state.write_u8(self.lcd_x);
+        state.write_u8(self.discard);
+        state.write_u8(self.delay);
+        state.write_bool(self.window);
+        state.write_u8(self.bg.len() as u8);
+        for pixel in self.bg.iter() {
+            state.write_u8(pixel.color);
+            state.write_u8(pixel.attributes)
+        }
+        state.write_u8(self.obj.len() as u8);
+        for pixel in self.obj.iter() {
+            state.write_u8(pixel.color);
+            state.write_u8(pixel.attributes);
+            state.write_u8(pixel.oam_index)
+        }
+        state.write_u8(match self.step {
+            FetcherStep::TileNumber => 0,
+            FetcherStep::DataLow => 1,
+            FetcherStep::DataHigh => 2,
+            FetcherStep::Push => 3,
+        });
+        state.write_u8(self.step_dots);
+        state.write_u8(self.fetcher_x);
+        state.write_u16(self.tile_line);
+        state.write_u8(self.tile_num);
+        state.write_u8(self.tile_attributes);
+        state.write_u8(self.data_low);
+        state.write_u8(self.data_high);
+        state.write_u8(self.sprites.len() as u8);
+        state.write_bytes(&self.sprites);
+        state.write_u8(self.fetched_sprites as u8);
+        state.write_u8(self.sprite_stall);
+        state.write_u8(self.pending_sprite)
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.active = state.read_bool()?;
+        self.lcd_x = state.read_u8()?;
+        self.discard = state.read_u8()?;
+        self.delay = state.read_u8()?;
+        self.window = state.read_bool()?;
+        self.bg.clear();
+        for _ in 0..state.read_u8()? {
+            self.bg.push_back(BgPixel {
+                color: state.read_u8()? & 0b11,
+                attributes: state.read_u8()?,
+            })
+        }
+        self.obj.clear();
+        for _ in 0..state.read_u8()? {
+            self.obj.push_back(ObjPixel {
+                color: state.read_u8()? & 0b11,
+                attributes: state.read_u8()?,
+                oam_index: state.read_u8()?,
+            })
+        }
+        self.step = match state.read_u8()? {
+            0 => FetcherStep::TileNumber,
+            1 => FetcherStep::DataLow,
+            2 => FetcherStep::DataHigh,
+            3 => FetcherStep::Push,
+            _ => return Err(StateError::InvalidFormat),
+        };
+        self.step_dots = state.read_u8()?;
+        self.fetcher_x = state.read_u8()?;
+        self.tile_line = state.read_u16()? & 0b111;
+        self.tile_num = state.read_u8()?;
+        self.tile_attributes = state.read_u8()?;
+        self.data_low = state.read_u8()?;
+        self.data_high = state.read_u8()?;
+        self.sprites.clear();
+        for _ in 0..state.read_u8()? {
+            let sprite = state.read_u8()?;
+            self.sprites.push(sprite)
+        }
+        self.fetched_sprites = state.read_u8()? as usize;
+        self.sprite_stall = state.read_u8()?;
+        self.pending_sprite = state.read_u8()?;
+        let sprite_valid = |sprite: &u8| (*sprite as usize) < OAM_SPRITES;
+        if self.lcd_x as usize > 160 || self.bg.len() > 16 || self.obj.len() > 8 ||
+           !self.sprites.iter().all(&sprite_valid) || !sprite_valid(&self.pending_sprite) {
+            return Err(StateError::InvalidFormat);
+        }
+        Ok(())
+    }
+
+    // Restarts tile fetching from the first column, used when the window starts
+    pub fn reset_fetcher(&mut self) {
+        self.step = FetcherStep::TileNumber;
+        self.step_dots = 0;
+        self.fetcher_x = 0
+    }
+
+    // Returns true when the current fetcher step finishes on this dot
+    pub fn tick_fetcher(&mut self) -> bool {
+        self.step_dots += 1;
+        self.step_dots == 2
+    }
+
+    pub fn next_step(&mut self) {
+        self.step_dots = 0;
+        self.step = self.step.next()
+    }
+
+    // The fetched tile row is pushed once the background FIFO is empty
+    pub fn push_bg_row(&mut self, x_flip: bool) -> bool {
+        if !self.bg.is_empty() {
+            return false;
+        }
+
+        for i in 0..8 {
+            let bit = if x_flip { i } else { 7 - i };
+            let color = (((self.data_high >> bit) & 0b1) << 1) | ((self.data_low >> bit) & 0b1);
+            self.bg.push_back(BgPixel {
+                color,
+                attributes: self.tile_attributes,
+            })
+        }
+        self.fetcher_x = self.fetcher_x.wrapping_add(1);
+        self.next_step();
+        true
+    }
+
+    // Mixes a fetched sprite row into the sprite FIFO. Pixels already in the FIFO stay on top
+    // unless they are transparent, or when oam_priority is set and the new sprite comes first
+    // in OAM.
+    pub fn merge_obj_row(&mut self, pixels: [ObjPixel; 8], skip: usize, oam_priority: bool) {
+        for (slot, &pixel) in pixels[skip..].iter().enumerate() {
+            if slot < self.obj.len() {
+                let existing = self.obj[slot];
+                let replace = existing.color == 0 ||
+                              (oam_priority && pixel.color != 0 &&
+                               pixel.oam_index < existing.oam_index);
+                if replace {
+                    self.obj[slot] = pixel
+                }
+            } else {
+                self.obj.push_back(pixel)
+            }
+        }
+    }
+}
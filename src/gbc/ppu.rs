@@ -1,7 +1,13 @@
 use super::Interrupts;
+use super::GameboyType;
+use super::palette::Palette;
+use super::pixel_fifo::{PixelFifo,FetcherStep,BgPixel,ObjPixel,SPRITE_FETCH_DOTS,
+                        MAX_SPRITES_PER_LINE};
 use super::{INT_VBLANK, INT_LCDSTAT};
+use super::save_state::{StateWriter,StateReader,StateError};
+use super::bess::{BessState,copy_buffer};
 
-#[derive(Debug,PartialEq,Eq)]
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
 struct Color {
     r: u8,
     g: u8,
@@ -9,32 +15,114 @@ struct Color {
     a: u8,
 }
 
-const WHITE: Color = Color {
-    r: 224,
-    g: 248,
-    b: 208,
-    a: 255,
-};
-const LIGHT_GRAY: Color = Color {
-    r: 136,
-    g: 192,
-    b: 112,
-    a: 255,
-};
-const DARK_GRAY: Color = Color {
-    r: 39,
-    g: 80,
-    b: 70,
-    a: 255,
-};
-const BLACK: Color = Color {
-    r: 8,
-    g: 24,
-    b: 32,
-    a: 255,
-};
-
-#[derive(Debug)]
+impl Color {
+    fn from_rgb(rgb: u32) -> Color {
+        Color {
+            r: (rgb >> 16) as u8,
+            g: (rgb >> 8) as u8,
+            b: rgb as u8,
+            a: 255,
+        }
+    }
+}
+
+#[derive(Debug,Clone,Copy)]
+enum PaletteSelect {
+    DmgBackground(u8),
+    DmgObject(u8, u8),
+    CgbBackground(u8),
+    CgbObject(u8),
+    // DMG compatibility mode on CGB, the DMG palette register picks a color from
+    // CGB palette memory
+    CompatBackground(u8),
+    CompatObject(u8, u8),
+}
+
+// CGB palette memory, accessed through an index register (BCPS/OCPS) and a data register
+// (BCPD/OCPD). Each of the 8 palettes holds 4 little-endian 15-bit RGB colors.
+#[derive(Clone)]
+struct PaletteRam {
+    index: u8,
+    auto_increment: bool,
+    data: [u8; PALETTE_RAM_SIZE],
+}
+
+impl PaletteRam {
+    fn new() -> PaletteRam {
+        PaletteRam {
+            index: 0,
+            auto_increment: false,
+            data: [0xff; PALETTE_RAM_SIZE],
+        }
+    }
+
+    fn get_spec(&self) -> u8 {
+        let mut flags = self.index | 0b0100_0000;
+        if self.auto_increment {
+            flags |= 0b1000_0000
+        }
+        flags
+    }
+
+    fn set_spec(&mut self, val: u8) {
+        self.index = val & 0x3f;
+        self.auto_increment = (val & 0b1000_0000) != 0
+    }
+
+    fn read_data(&self, blocked: bool) -> u8 {
+        if blocked {
+            0xff
+        } else {
+            self.data[self.index as usize]
+        }
+    }
+
+    // Writes while the PPU is reading palette memory are dropped, but the index is still
+    // incremented
+    fn write_data(&mut self, val: u8, blocked: bool) {
+        if !blocked {
+            self.data[self.index as usize] = val
+        }
+        if self.auto_increment {
+            self.index = (self.index + 1) & 0x3f
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.get_spec());
+        state.write_bytes(&self.data)
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let spec = state.read_u8()?;
+        self.set_spec(spec);
+        state.read_bytes(&mut self.data)
+    }
+
+    fn set_color(&mut self, palette_num: usize, color_id: usize, rgb: u16) {
+        let offset = palette_num * 8 + color_id * 2;
+        self.data[offset] = rgb as u8;
+        self.data[offset + 1] = (rgb >> 8) as u8
+    }
+
+    fn get_color(&self, palette_num: u8, color_id: u8) -> Color {
+        let offset = (palette_num as usize & 0b111) * 8 + color_id as usize * 2;
+        let rgb = (self.data[offset] as u16) | ((self.data[offset + 1] as u16) << 8);
+
+        let r = (rgb & 0x1f) as u8;
+        let g = ((rgb >> 5) & 0x1f) as u8;
+        let b = ((rgb >> 10) & 0x1f) as u8;
+
+        Color {
+            r: (r << 3) | (r >> 2),
+            g: (g << 3) | (g >> 2),
+            b: (b << 3) | (b >> 2),
+            a: 255,
+        }
+    }
+}
+
+#[derive(Debug,Clone)]
 struct LCDCtrl {
     lcd_display_enable: bool,
     window_tile_map_display_select: bool,
@@ -103,6 +191,7 @@ impl LCDCtrl {
     }
 }
 
+#[derive(Clone)]
 struct LCDStat {
     lyc_ly_interrupt: bool,
     oam_interrupt: bool,
@@ -145,6 +234,18 @@ impl LCDStat {
         flags
     }
 
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.get_flags())
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let flags = state.read_u8()?;
+        self.set_flags(flags);
+        self.coincidence_flag = (flags & 0b0000_0100) != 0;
+        self.mode = Mode::from_flags(flags);
+        Ok(())
+    }
+
     fn set_flags(&mut self, flags: u8) {
         self.lyc_ly_interrupt = (flags & 0b0100_0000) != 0;
         self.oam_interrupt = (flags & 0b0010_0000) != 0;
@@ -157,7 +258,7 @@ impl LCDStat {
     }
 }
 
-#[derive(Debug,Clone,Copy)]
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
 enum Mode {
     HBlank,
     VBlank,
@@ -175,33 +276,67 @@ impl Mode {
         };
         f as u8
     }
+
+    fn from_flags(flags: u8) -> Mode {
+        match (flags & 0b11) as u32 {
+            MODE_HBLANK => Mode::HBlank,
+            MODE_VBLANK => Mode::VBlank,
+            MODE_OAM => Mode::Oam,
+            _ => Mode::VRam,
+        }
+    }
 }
 
 pub const OAM_SIZE: usize = 0x100; // 40 OBJs - 32 bits
+// The rest of the OAM area is not backed by sprite attributes
+const OAM_SPRITES_SIZE: usize = 0xa0;
 
 const FRAMEBUFFER_SIZE: usize = DISPLAY_WIDTH * DISPLAY_HEIGHT;
 
-const CLKS_SCREEN_REFRESH: u32 = 70224;
+pub const CLKS_SCREEN_REFRESH: u32 = 70224;
 const DISPLAY_WIDTH: usize = 160;
 const DISPLAY_HEIGHT: usize = 144;
 
 const VRAM_SIZE: usize = 1024 * 16;
+const PALETTE_RAM_SIZE: usize = 64;
 
 const MODE_HBLANK: u32 = 0;
 const MODE_VBLANK: u32 = 1;
 const MODE_OAM: u32 = 2;
 const MODE_VRAM: u32 = 3;
 
-const HBLANK_CYCLES: u32 = 204;
-const VBLANK_CYCLES: u32 = 456;
+const LINE_CYCLES: u32 = 456;
 const OAM_CYCLES: u32 = 80;
+// Shortest pixel transfer, without fine scroll, window or sprites
 const VRAM_CYCLES: u32 = 172;
+// The background fetcher restarts when the window starts
+const WINDOW_FETCH_CYCLES: u32 = 6;
+// LY reads 153 only for the first M-cycle of the last line
+const LY_153_CYCLES: u32 = 4;
+
+#[derive(Debug,Copy,Clone,PartialEq,Eq)]
+pub enum Renderer {
+    // Draws every line at once at the end of pixel transfer
+    Scanline,
+    // Draws one pixel per dot, so register writes during pixel transfer take effect
+    // mid-line
+    PixelFifo,
+}
 
 pub trait VideoSink {
-    fn frame_available(&mut self, frame: &Box<[u32]>);
+    fn frame_available(&mut self, frame: &[u32]);
 }
 
+#[derive(Clone)]
 pub struct Ppu {
+    gameboy_type: GameboyType,
+    dmg_compatibility: bool,
+    opri: u8, // Object priority mode, 0 - OAM index (CGB), 1 - X coordinate (DMG)
+    dmg_palette: Palette,
+    renderer: Renderer,
+    sprite_limit: bool,
+    access_restrictions: bool,
+    fifo: PixelFifo,
     lcdc: LCDCtrl,
     lcdstat: LCDStat,
     scx: u8,
@@ -213,19 +348,40 @@ pub struct Ppu {
     obp_1: u8, // Object palette 1 data
     window_y: u8,
     window_x: u8,
-    bgpi: u8,
-    bgpd: u8,
+    // The window has its own line counter that only advances on lines it is drawn on
+    window_line: u8,
+    window_y_triggered: bool,
+    window_drawn: bool,
+    window_wrap: bool,
+    bg_palette_ram: PaletteRam,
+    obj_palette_ram: PaletteRam,
     vbk: u8,
     vram: Box<[u8]>,
     oam: Box<[u8]>,
     framebuffer: Box<[u32]>,
-    mode_cycles: u32,
-    cycles: u32,
+    bg_line: [u8; DISPLAY_WIDTH],
+    bg_priority: [bool; DISPLAY_WIDTH],
+    line_cycles: u32,
+    vram_cycles: u32,
+    first_line: bool,
+    skip_frame: bool,
+    stat_line: bool,
+    line_sprites: Vec<u8>,
+    hblank_started: bool,
+    frame_ready: bool,
 }
 
 impl Ppu {
-    pub fn new() -> Ppu {
+    pub fn new(gameboy_type: GameboyType) -> Ppu {
         Ppu {
+            gameboy_type,
+            dmg_compatibility: false,
+            opri: 0,
+            dmg_palette: Palette::dmg_green(),
+            renderer: Renderer::Scanline,
+            sprite_limit: true,
+            access_restrictions: true,
+            fifo: PixelFifo::new(),
             lcdc: LCDCtrl::new(),
             lcdstat: LCDStat::new(),
             scx: 0,
@@ -234,29 +390,105 @@ impl Ppu {
             lyc: 0xff,
             window_y: 0,
             window_x: 0,
+            window_line: 0,
+            window_y_triggered: false,
+            window_drawn: false,
+            window_wrap: false,
             bgp: 0xfc,
             obp_0: 0xff,
             obp_1: 0xff,
-            bgpi: 0x00,
-            bgpd: 0x00,
+            bg_palette_ram: PaletteRam::new(),
+            obj_palette_ram: PaletteRam::new(),
             vbk: 0,
             vram: vec![0; VRAM_SIZE].into_boxed_slice(),
             oam: vec![0; OAM_SIZE].into_boxed_slice(),
             framebuffer: vec![0; FRAMEBUFFER_SIZE].into_boxed_slice(),
-            mode_cycles: 0,
-            cycles: 0,
+            bg_line: [0; DISPLAY_WIDTH],
+            bg_priority: [false; DISPLAY_WIDTH],
+            line_cycles: 0,
+            vram_cycles: VRAM_CYCLES,
+            first_line: false,
+            skip_frame: false,
+            stat_line: false,
+            line_sprites: Vec::with_capacity(MAX_SPRITES_PER_LINE),
+            hblank_started: false,
+            frame_ready: false,
+        }
+    }
+
+    // The state the boot ROM starts from, with the LCD off
+    pub fn power_on(gameboy_type: GameboyType) -> Ppu {
+        let mut ppu = Ppu::new(gameboy_type);
+        ppu.lcdc.set_flags(0);
+        ppu.lcdstat.mode = Mode::HBlank;
+        ppu.ly = 0;
+        ppu.lyc = 0;
+        ppu.bgp = 0;
+        ppu.obp_0 = 0;
+        ppu.obp_1 = 0;
+        ppu
+    }
+
+    // A CGB running a DMG cart renders with the DMG registers, using colors from palette
+    // memory as set up by the boot ROM
+    pub fn set_dmg_compatibility(&mut self, dmg_compatibility: bool) {
+        self.dmg_compatibility = dmg_compatibility
+    }
+
+    pub fn dmg_compatibility(&self) -> bool {
+        self.dmg_compatibility
+    }
+
+    // Takes effect from the next line
+    pub fn set_renderer(&mut self, renderer: Renderer) {
+        self.renderer = renderer
+    }
+
+    // Without the limit every sprite on a line is drawn, the line timing still only
+    // accounts for the first 10
+    pub fn set_sprite_limit(&mut self, sprite_limit: bool) {
+        self.sprite_limit = sprite_limit
+    }
+
+    // Whether the CPU is locked out of VRAM and OAM while the PPU uses them
+    pub fn set_access_restrictions(&mut self, access_restrictions: bool) {
+        self.access_restrictions = access_restrictions
+    }
+
+    // Sets the colors used for DMG graphics. In DMG compatibility mode they are loaded into
+    // CGB palette memory instead, replacing the colors picked by the boot ROM.
+    pub fn set_dmg_palette(&mut self, palette: Palette) {
+        self.dmg_palette = palette;
+        if self.dmg_compatibility {
+            self.set_compatibility_palettes(&Palette::to_rgb15(&palette.bg),
+                                            &Palette::to_rgb15(&palette.obj_0),
+                                            &Palette::to_rgb15(&palette.obj_1))
+        }
+    }
+
+    // Loads a 4 color BG, OBJ0 and OBJ1 palette into CGB palette memory for DMG
+    // compatibility mode. Colors are 15-bit RGB.
+    pub fn set_compatibility_palettes(&mut self, bg: &[u16; 4], obj_0: &[u16; 4], obj_1: &[u16; 4]) {
+        for i in 0..4 {
+            self.bg_palette_ram.set_color(0, i, bg[i]);
+            self.obj_palette_ram.set_color(0, i, obj_0[i]);
+            self.obj_palette_ram.set_color(1, i, obj_1[i])
         }
     }
 
     pub fn write(&mut self, addr: u16, val: u8) {
         match addr {
-            0x8000...0x9fff => {
+            0xff6c if self.gameboy_type == GameboyType::Dmg => {}
+            0xff6c => self.opri = val & 0b1,
+            0x8000..=0x9fff if self.vram_blocked() => {}
+            0x8000..=0x9fff => {
                 let addr = addr - 0x8000;
                 let offset = self.vbk_offset();
                 self.vram[(addr + offset) as usize] = val
             }
-            0xfe00...0xfeff => self.oam[(addr - 0xfe00) as usize] = val,
-            0xff40 => self.lcdc.set_flags(val),
+            0xfe00..=0xfeff if self.oam_blocked() => {}
+            0xfe00..=0xfeff => self.oam[(addr - 0xfe00) as usize] = val,
+            0xff40 => self.write_lcdc(val),
             0xff41 => self.lcdstat.set_flags(val),
             0xff42 => self.scy = val,
             0xff43 => self.scx = val,
@@ -267,22 +499,59 @@ impl Ppu {
             0xff49 => self.obp_1 = val,
             0xff4a => self.window_y = val,
             0xff4b => self.window_x = val,
-            0xff4f => self.vbk = val,
-            0xff68 => self.bgpi = val,
-            0xff69 => self.bgpd = val,
+            0xff4f => self.vbk = val & 0b1,
+            0xff68..=0xff6b if self.gameboy_type == GameboyType::Dmg => {}
+            0xff68 => self.bg_palette_ram.set_spec(val),
+            0xff69 => {
+                let blocked = self.palette_ram_blocked();
+                self.bg_palette_ram.write_data(val, blocked)
+            }
+            0xff6a => self.obj_palette_ram.set_spec(val),
+            0xff6b => {
+                let blocked = self.palette_ram_blocked();
+                self.obj_palette_ram.write_data(val, blocked)
+            }
             _ => panic!("Write not implmented for 0x{:x}", addr),
         }
     }
 
+    fn write_lcdc(&mut self, val: u8) {
+        let was_enabled = self.lcdc.lcd_display_enable;
+        self.lcdc.set_flags(val);
+
+        if was_enabled != self.lcdc.lcd_display_enable {
+            // LY and the STAT mode read 0 while the LCD is off, and the first line starts
+            // when it is turned back on
+            self.ly = 0;
+            self.line_cycles = 0;
+            self.stat_line = false;
+            self.reset_window();
+            if self.lcdc.lcd_display_enable {
+                // The first line skips the OAM scan, and the first frame is not shown
+                self.lcdstat.mode = Mode::Oam;
+                self.first_line = true;
+                self.skip_frame = true
+            } else {
+                self.lcdstat.mode = Mode::HBlank;
+                self.fifo.active = false
+            }
+        }
+    }
+
     pub fn read(&self, addr: u16) -> u8 {
         match addr {
-            0x8000...0x9fff => {
+            0xff6c if self.gameboy_type == GameboyType::Dmg => 0xff,
+            0xff6c => self.opri | 0b1111_1110,
+            0x8000..=0x9fff if self.vram_blocked() => 0xff,
+            0x8000..=0x9fff => {
                 let addr = addr - 0x8000;
                 let offset = self.vbk_offset();
                 self.vram[(addr + offset) as usize]
             }
-            0xfe00...0xfeff => self.oam[(addr - 0xfe00) as usize],
+            0xfe00..=0xfeff if self.oam_blocked() => 0xff,
+            0xfe00..=0xfeff => self.oam[(addr - 0xfe00) as usize],
             0xff40 => self.lcdc.get_flags(),
+            0xff41 if self.first_line => self.lcdstat.get_flags() & !0b11,
             0xff41 => self.lcdstat.get_flags(),
             0xff42 => self.scy,
             0xff43 => self.scx,
@@ -293,118 +562,751 @@ impl Ppu {
             0xff49 => self.obp_1,
             0xff4a => self.window_y,
             0xff4b => self.window_x,
-            0xff4f => self.vbk,
-            0xff68 => self.bgpi,
-            0xff69 => self.bgpd,
+            0xff4f => {
+                match self.gameboy_type {
+                    GameboyType::Cgb => self.vbk | 0b1111_1110,
+                    GameboyType::Dmg => 0xff,
+                }
+            }
+            0xff68..=0xff6b if self.gameboy_type == GameboyType::Dmg => 0xff,
+            0xff68 => self.bg_palette_ram.get_spec(),
+            0xff69 => self.bg_palette_ram.read_data(self.palette_ram_blocked()),
+            0xff6a => self.obj_palette_ram.get_spec(),
+            0xff6b => self.obj_palette_ram.read_data(self.palette_ram_blocked()),
             _ => panic!("Read not implmented for 0x{:x}", addr),
         }
     }
 
-    #[allow(unused_variables)]
-    pub fn cycle_flush(&mut self, cycle_count: u32, video_sink: &mut dyn VideoSink) -> Interrupts {
-        self.mode_cycles += cycle_count;
-
+    pub fn cycle_flush(&mut self, cycle_count: u32) -> Interrupts {
         let mut interrupt = Interrupts::empty();
 
         if self.lcdc.lcd_display_enable {
+            // Step from one PPU event to the next so that no mode change is skipped
+            let mut remaining = cycle_count;
+            while remaining > 0 {
+                let dots = remaining.min(self.cycles_to_next_event());
+                remaining -= dots;
+                interrupt |= self.step(dots);
+
+                if self.update_stat_line() {
+                    interrupt |= INT_LCDSTAT
+                }
+            }
+        } else {
+            // Frames keep their timing while the LCD is off, showing a blank screen
+            self.line_cycles += cycle_count;
+            if self.line_cycles >= CLKS_SCREEN_REFRESH {
+                self.line_cycles -= CLKS_SCREEN_REFRESH;
+                self.clear_framebuffer();
+                self.frame_ready = true
+            }
+        }
+        interrupt
+    }
+
+    fn cycles_to_next_event(&self) -> u32 {
+        match self.lcdstat.mode {
+            Mode::Oam => OAM_CYCLES - self.line_cycles,
+            Mode::VRam if self.fifo.active => 1,
+            Mode::VRam => OAM_CYCLES + self.vram_cycles - self.line_cycles,
+            Mode::VBlank if self.ly == 153 => LY_153_CYCLES - self.line_cycles,
+            Mode::HBlank | Mode::VBlank => LINE_CYCLES - self.line_cycles,
+        }
+    }
+
+    fn step(&mut self, dots: u32) -> Interrupts {
+        self.line_cycles += dots;
+
+        match self.lcdstat.mode {
+            Mode::Oam => {
+                if self.line_cycles == OAM_CYCLES {
+                    self.first_line = false;
+                    self.lcdstat.mode = Mode::VRam;
+                    self.scan_oam();
+                    if self.renderer == Renderer::PixelFifo {
+                        self.start_fifo_line()
+                    } else {
+                        self.vram_cycles = self.vram_cycles()
+                    }
+                }
+            }
 
-            self.cycles += cycle_count;
-            let cycles = self.mode_cycles;
+            Mode::VRam if self.fifo.active => {
+                self.fifo_dot();
+                if !self.fifo.active {
+                    self.enter_hblank()
+                }
+            }
 
-            match self.lcdstat.mode {
-                Mode::HBlank => {
-                    if cycles >= HBLANK_CYCLES {
-                        self.mode_cycles -= HBLANK_CYCLES;
+            Mode::VRam => {
+                if self.line_cycles == OAM_CYCLES + self.vram_cycles {
+                    self.draw_scanline();
+                    self.enter_hblank()
+                }
+            }
 
-                        self.lcdstat.coincidence_flag = self.ly == self.lyc;
+            // LY reads 0 for all but the first M-cycle of the last line
+            Mode::VBlank if self.ly == 153 => {
+                if self.line_cycles == LY_153_CYCLES {
+                    self.ly = 0
+                }
+            }
 
-                        if self.lcdstat.lyc_ly_interrupt && self.lcdstat.coincidence_flag {
-                            interrupt |= INT_LCDSTAT
-                        }
+            Mode::HBlank | Mode::VBlank => {
+                if self.line_cycles == LINE_CYCLES {
+                    // The last line of VBlank has already reset LY to 0
+                    let ly = if self.lcdstat.mode == Mode::VBlank && self.ly == 0 {
+                        0
+                    } else {
+                        self.ly + 1
+                    };
+                    return self.start_line(ly);
+                }
+            }
+        }
+        Interrupts::empty()
+    }
 
-                        self.lcdstat.mode = if self.ly == 144 {
+    fn start_line(&mut self, ly: u8) -> Interrupts {
+        self.line_cycles = 0;
+        self.ly = ly;
 
-                            video_sink.frame_available(&self.framebuffer);
+        if self.window_drawn {
+            self.window_line = self.window_line.wrapping_add(1);
+            self.window_drawn = false
+        }
 
-                            interrupt |= INT_VBLANK;
+        if (ly as usize) < DISPLAY_HEIGHT {
+            // WY is only compared with LY at the start of each line
+            if ly == self.window_y {
+                self.window_y_triggered = true
+            }
+            self.lcdstat.mode = Mode::Oam;
+            Interrupts::empty()
+        } else if ly as usize == DISPLAY_HEIGHT {
+            self.reset_window();
+            self.lcdstat.mode = Mode::VBlank;
+            if self.skip_frame {
+                self.clear_framebuffer();
+                self.skip_frame = false
+            }
+            self.frame_ready = true;
+            INT_VBLANK
+        } else {
+            Interrupts::empty()
+        }
+    }
 
-                            if self.lcdstat.vblank_interrupt {
-                                interrupt |= INT_LCDSTAT
-                            }
+    fn enter_hblank(&mut self) {
+        // A window started at WX 166 carries over and covers the whole next line
+        self.window_wrap = self.window_drawn && self.window_x == 166;
+        self.lcdstat.mode = Mode::HBlank;
+        self.hblank_started = true
+    }
 
-                            self.cycles = 0;
+    fn reset_window(&mut self) {
+        self.window_line = 0;
+        self.window_y_triggered = false;
+        self.window_drawn = false;
+        self.window_wrap = false
+    }
 
-                            Mode::VBlank
-                        } else {
+    // Returns the screen x the window starts at on this line and the number of window pixels
+    // cut off at the left edge. WX 0 to 6 moves the window partly off screen, at 0 the
+    // window is also shifted by the fine scroll.
+    fn window_start(&self) -> Option<(u8, u8)> {
+        let bg_enabled = self.cgb_mode() || self.lcdc.bg_display;
+        if !bg_enabled || !self.lcdc.window_display_enable || !self.window_y_triggered {
+            return None;
+        }
+        if self.window_wrap {
+            return Some((0, 0));
+        }
+        match self.window_x {
+            0 => Some((0, 7 + (self.scx & 0b111))),
+            1..=6 => Some((0, 7 - self.window_x)),
+            7..=166 => Some((self.window_x - 7, 0)),
+            _ => None,
+        }
+    }
 
-                            if self.lcdstat.hblank_interrupt {
-                                interrupt |= INT_LCDSTAT
-                            }
+    // The STAT interrupt is requested when any of the enabled sources becomes active while
+    // none of them was. Sources that overlap block each other ("STAT IRQ blocking").
+    fn update_stat_line(&mut self) -> bool {
+        self.lcdstat.coincidence_flag = self.ly == self.lyc;
+
+        let stat = &self.lcdstat;
+        let mode_source = match stat.mode {
+            Mode::HBlank => stat.hblank_interrupt,
+            // The OAM source also fires when VBlank starts
+            Mode::VBlank => {
+                stat.vblank_interrupt ||
+                (stat.oam_interrupt && self.ly as usize == DISPLAY_HEIGHT && self.line_cycles == 0)
+            }
+            Mode::Oam => stat.oam_interrupt && !self.first_line,
+            Mode::VRam => false,
+        };
+        let stat_line = mode_source || (stat.lyc_ly_interrupt && stat.coincidence_flag);
 
-                            self.draw_scanline();
-                            Mode::Oam
-                        };
+        let rising_edge = stat_line && !self.stat_line;
+        self.stat_line = stat_line;
+        rising_edge
+    }
 
-                        self.ly = self.ly + 1;
-                    }
+    // Picks the first sprites in OAM that overlap the current line
+    fn scan_oam(&mut self) {
+        self.line_sprites.clear();
+        let height = if self.lcdc.obj_size { 16 } else { 8 };
+        let line = self.ly as u16 + 16;
+        for sprite in 0..40 {
+            let y_pos = self.oam[sprite * 4] as u16;
+            if line >= y_pos && line < y_pos + height {
+                self.line_sprites.push(sprite as u8);
+                if self.sprite_limit && self.line_sprites.len() == MAX_SPRITES_PER_LINE {
+                    break;
                 }
+            }
+        }
+    }
 
-                Mode::VBlank => {
-                    if cycles >= VBLANK_CYCLES {
-                        self.mode_cycles -= VBLANK_CYCLES;
+    // Length of pixel transfer for the scanline renderer. It is extended by the fine scroll,
+    // the window and by sprites, which stall the background fetcher until its current tile
+    // is fetched.
+    fn vram_cycles(&self) -> u32 {
+        let mut cycles = VRAM_CYCLES + (self.scx & 0b111) as u32;
 
-                        self.lcdstat.coincidence_flag = self.ly == self.lyc;
-                        if self.lcdstat.lyc_ly_interrupt && self.lcdstat.coincidence_flag {
-                            interrupt |= INT_LCDSTAT
-                        }
+        if self.window_start().is_some() {
+            cycles += WINDOW_FETCH_CYCLES
+        }
 
-                        self.ly = self.ly + 1;
+        if self.lcdc.obj_display_enable {
+            let mut fetched_tiles = Vec::with_capacity(MAX_SPRITES_PER_LINE);
+            for &sprite in self.line_sprites.iter().take(MAX_SPRITES_PER_LINE) {
+                let x_pos = self.oam[sprite as usize * 4 + 1] as u32;
+                if x_pos >= 168 {
+                    continue;
+                }
+                cycles += SPRITE_FETCH_DOTS as u32;
+
+                let x = x_pos + self.scx as u32;
+                let tile = x / 8;
+                if x_pos == 0 {
+                    cycles += 5
+                } else if !fetched_tiles.contains(&tile) {
+                    cycles += 5u32.saturating_sub(x % 8);
+                    fetched_tiles.push(tile)
+                }
+            }
+        }
+        cycles
+    }
 
-                        if self.ly == 154 {
-                            self.lcdstat.mode = Mode::Oam;
-                            self.ly = 0;
+    // Returns true once for every HBlank that has been entered since the last call
+    pub fn take_hblank_started(&mut self) -> bool {
+        let hblank_started = self.hblank_started;
+        self.hblank_started = false;
+        hblank_started
+    }
 
-                            if self.lcdstat.oam_interrupt {
-                                interrupt |= INT_LCDSTAT
-                            }
+    // Returns the frame once it has been completed, the next frame is not drawn before VBlank
+    // ends
+    pub fn take_frame(&mut self) -> Option<&[u32]> {
+        if self.frame_ready {
+            self.frame_ready = false;
+            Some(&self.framebuffer)
+        } else {
+            None
+        }
+    }
 
-                        }
-                    }
+    // Settings like the renderer and DMG palette are not part of the state
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.dmg_compatibility);
+        state.write_u8(self.opri);
+        state.write_u8(self.lcdc.get_flags());
+        self.lcdstat.save_state(state);
+        state.write_u8(self.scx);
+        state.write_u8(self.scy);
+        state.write_u8(self.ly);
+        state.write_u8(self.lyc);
+        state.write_u8(self.bgp);
+        state.write_u8(self.obp_0);
+        state.write_u8(self.obp_1);
+        state.write_u8(self.window_y);
+        state.write_u8(self.window_x);
+        state.write_u8(self.window_line);
+        state.write_bool(self.window_y_triggered);
+        state.write_bool(self.window_drawn);
+        state.write_bool(self.window_wrap);
+        self.bg_palette_ram.save_state(state);
+        self.obj_palette_ram.save_state(state);
+        state.write_u8(self.vbk);
+        state.write_bytes(&self.vram);
+        state.write_bytes(&self.oam);
+        for &pixel in self.framebuffer.iter() {
+            state.write_u32(pixel)
+        }
+        state.write_u32(self.line_cycles);
+        state.write_u32(self.vram_cycles);
+        state.write_bool(self.first_line);
+        state.write_bool(self.skip_frame);
+        state.write_bool(self.stat_line);
+        state.write_u8(self.line_sprites.len() as u8);
+        state.write_bytes(&self.line_sprites);
+        state.write_bool(self.hblank_started);
+        self.fifo.save_state(state)
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.dmg_compatibility = state.read_bool()?;
+        self.opri = state.read_u8()? & 0b1;
+        let lcdc = state.read_u8()?;
+        self.lcdc.set_flags(lcdc);
+        self.lcdstat.load_state(state)?;
+        self.scx = state.read_u8()?;
+        self.scy = state.read_u8()?;
+        self.ly = state.read_u8()?;
+        self.lyc = state.read_u8()?;
+        self.bgp = state.read_u8()?;
+        self.obp_0 = state.read_u8()?;
+        self.obp_1 = state.read_u8()?;
+        self.window_y = state.read_u8()?;
+        self.window_x = state.read_u8()?;
+        self.window_line = state.read_u8()?;
+        self.window_y_triggered = state.read_bool()?;
+        self.window_drawn = state.read_bool()?;
+        self.window_wrap = state.read_bool()?;
+        self.bg_palette_ram.load_state(state)?;
+        self.obj_palette_ram.load_state(state)?;
+        self.vbk = state.read_u8()? & 0b1;
+        state.read_bytes(&mut self.vram)?;
+        state.read_bytes(&mut self.oam)?;
+        for pixel in self.framebuffer.iter_mut() {
+            *pixel = state.read_u32()?
+        }
+        self.line_cycles = state.read_u32()?;
+        self.vram_cycles = state.read_u32()?;
+        self.first_line = state.read_bool()?;
+        self.skip_frame = state.read_bool()?;
+        self.stat_line = state.read_bool()?;
+        self.line_sprites.clear();
+        for _ in 0..state.read_u8()? {
+            let sprite = state.read_u8()?;
+            self.line_sprites.push(sprite)
+        }
+        self.hblank_started = state.read_bool()?;
+        self.fifo.load_state(state)?;
+        self.frame_ready = false;
+
+        if !self.valid_line_timing() ||
+           self.line_sprites.iter().any(|&sprite| sprite as usize >= OAM_SIZE / 4) {
+            return Err(StateError::InvalidFormat);
+        }
+        Ok(())
+    }
+
+    // The position in the line has to fit the mode and LY, otherwise stepping to the next
+    // event would skip past it
+    fn valid_line_timing(&self) -> bool {
+        let visible = (self.ly as usize) < DISPLAY_HEIGHT;
+        let line_cycles = self.line_cycles;
+        match self.lcdstat.mode {
+            Mode::Oam => visible && line_cycles < OAM_CYCLES,
+            Mode::VRam if self.fifo.active => {
+                visible && (OAM_CYCLES..LINE_CYCLES).contains(&line_cycles)
+            }
+            Mode::VRam => {
+                visible && OAM_CYCLES + self.vram_cycles < LINE_CYCLES &&
+                (OAM_CYCLES..OAM_CYCLES + self.vram_cycles).contains(&line_cycles)
+            }
+            Mode::HBlank => visible && line_cycles < LINE_CYCLES,
+            Mode::VBlank if self.ly == 153 => line_cycles < LY_153_CYCLES,
+            // LY already reads 0 for the rest of the last line
+            Mode::VBlank if self.ly == 0 => (LY_153_CYCLES..LINE_CYCLES).contains(&line_cycles),
+            Mode::VBlank => !visible && self.ly < 153 && line_cycles < LINE_CYCLES,
+        }
+    }
+
+    pub fn save_bess(&self, bess: &mut BessState) {
+        match self.gameboy_type {
+            GameboyType::Cgb => {
+                bess.vram = self.vram.to_vec();
+                bess.bg_palettes = self.bg_palette_ram.data.to_vec();
+                bess.obj_palettes = self.obj_palette_ram.data.to_vec()
+            }
+            GameboyType::Dmg => bess.vram = self.vram[..VRAM_SIZE / 2].to_vec(),
+        }
+        bess.oam = self.oam[..OAM_SPRITES_SIZE].to_vec()
+    }
+
+    // BESS only has the register values, so the current line is restarted from its first dot
+    pub fn load_bess(&mut self, bess: &BessState) -> Result<(), StateError> {
+        let io = &bess.io;
+        if io[0x44] > 153 {
+            return Err(StateError::InvalidFormat);
+        }
+
+        copy_buffer(&mut self.vram, &bess.vram);
+        copy_buffer(&mut self.oam[..OAM_SPRITES_SIZE], &bess.oam);
+        self.lcdc.set_flags(io[0x40]);
+        self.lcdstat.set_flags(io[0x41]);
+        self.scy = io[0x42];
+        self.scx = io[0x43];
+        self.lyc = io[0x45];
+        self.bgp = io[0x47];
+        self.obp_0 = io[0x48];
+        self.obp_1 = io[0x49];
+        self.window_y = io[0x4a];
+        self.window_x = io[0x4b];
+        if self.gameboy_type == GameboyType::Cgb {
+            copy_buffer(&mut self.bg_palette_ram.data, &bess.bg_palettes);
+            copy_buffer(&mut self.obj_palette_ram.data, &bess.obj_palettes);
+            self.vbk = io[0x4f] & 0b1;
+            self.bg_palette_ram.set_spec(io[0x68]);
+            self.obj_palette_ram.set_spec(io[0x6a]);
+            self.opri = io[0x6c] & 0b1
+        }
+
+        self.reset_window();
+        self.line_cycles = 0;
+        self.vram_cycles = VRAM_CYCLES;
+        self.first_line = false;
+        self.skip_frame = false;
+        self.line_sprites.clear();
+        self.hblank_started = false;
+        self.frame_ready = false;
+        self.fifo.active = false;
+
+        if self.lcdc.lcd_display_enable {
+            self.ly = io[0x44];
+            if (self.ly as usize) < DISPLAY_HEIGHT {
+                // Assume the window has been drawn on every line since WY
+                if self.ly >= self.window_y {
+                    self.window_y_triggered = true;
+                    self.window_line = self.ly - self.window_y
                 }
+                self.lcdstat.mode = Mode::Oam
+            } else {
+                self.lcdstat.mode = Mode::VBlank
+            }
+        } else {
+            self.ly = 0;
+            self.lcdstat.mode = Mode::HBlank
+        }
 
-                Mode::Oam => {
-                    if cycles >= OAM_CYCLES {
-                        self.mode_cycles -= OAM_CYCLES;
-                        self.lcdstat.mode = Mode::VRam
-                    }
+        // Pick up the current level of the STAT line without requesting an interrupt
+        self.update_stat_line();
+        Ok(())
+    }
+
+    pub fn lcd_enabled(&self) -> bool {
+        self.lcdc.lcd_display_enable
+    }
+
+    // OAM DMA writes are not affected by the PPU mode
+    pub fn oam_dma_write(&mut self, offset: u16, val: u8) {
+        self.oam[offset as usize] = val
+    }
+
+    // VRAM DMA writes go to the bank selected by VBK and are not affected by the PPU mode,
+    // so a general-purpose transfer started during pixel transfer still copies everything
+    pub fn hdma_write(&mut self, offset: u16, val: u8) {
+        let offset = offset + self.vbk_offset();
+        self.vram[offset as usize] = val
+    }
+
+    // VRAM bank 1 only exists on CGB
+    fn vbk_offset(&self) -> u16 {
+        match self.gameboy_type {
+            GameboyType::Cgb => self.vbk as u16 * 0x2000,
+            GameboyType::Dmg => 0,
+        }
+    }
+
+    // Palette memory is in use by the PPU during pixel transfer
+    fn palette_ram_blocked(&self) -> bool {
+        self.vram_blocked()
+    }
+
+    // VRAM is in use by the PPU during pixel transfer
+    fn vram_blocked(&self) -> bool {
+        match self.lcdstat.mode {
+            Mode::VRam => self.access_restrictions && self.lcdc.lcd_display_enable,
+            _ => false,
+        }
+    }
+
+    // OAM is in use by the PPU during the OAM scan and pixel transfer. The first line after
+    // the LCD is turned on has no OAM scan.
+    fn oam_blocked(&self) -> bool {
+        match self.lcdstat.mode {
+            Mode::Oam => self.access_restrictions && !self.first_line,
+            Mode::VRam => self.access_restrictions && self.lcdc.lcd_display_enable,
+            _ => false,
+        }
+    }
+
+    fn read_vram(&self, bank: u16, addr: u16) -> u8 {
+        self.vram[(bank * 0x2000 + addr - 0x8000) as usize]
+    }
+
+    fn cgb_mode(&self) -> bool {
+        self.gameboy_type == GameboyType::Cgb && !self.dmg_compatibility
+    }
+
+    fn bg_palette(&self, attributes: u8) -> PaletteSelect {
+        match self.gameboy_type {
+            GameboyType::Cgb if self.dmg_compatibility => {
+                PaletteSelect::CompatBackground(self.bgp)
+            }
+            GameboyType::Cgb => PaletteSelect::CgbBackground(attributes & 0b111),
+            GameboyType::Dmg => PaletteSelect::DmgBackground(self.bgp),
+        }
+    }
+
+    fn obj_palette(&self, attributes: u8) -> PaletteSelect {
+        let (obp_num, obp) = if (attributes & 0x10) != 0 {
+            (1, self.obp_1)
+        } else {
+            (0, self.obp_0)
+        };
+        match self.gameboy_type {
+            GameboyType::Cgb if self.dmg_compatibility => {
+                PaletteSelect::CompatObject(obp_num, obp)
+            }
+            GameboyType::Cgb => PaletteSelect::CgbObject(attributes & 0b111),
+            GameboyType::Dmg => PaletteSelect::DmgObject(obp_num, obp),
+        }
+    }
+
+    fn start_fifo_line(&mut self) {
+        self.fifo.start_line(self.scx);
+        self.fifo.sprites.extend_from_slice(&self.line_sprites);
+        self.bg_line = [0; DISPLAY_WIDTH];
+        self.bg_priority = [false; DISPLAY_WIDTH];
+    }
+
+    fn fifo_dot(&mut self) {
+        if self.fifo.delay > 0 {
+            self.fifo.delay -= 1;
+            return;
+        }
+
+        if self.fifo.sprite_stall > 0 {
+            // The background fetcher finishes its current tile before the sprite is fetched
+            if self.fifo.step != FetcherStep::Push {
+                self.fetcher_dot();
+                return;
+            }
+            self.fifo.sprite_stall -= 1;
+            if self.fifo.sprite_stall == 0 {
+                let sprite = self.fifo.pending_sprite;
+                self.fetch_sprite(sprite)
+            }
+            return;
+        }
+
+        if self.fifo.discard == 0 {
+            if let Some((start, cut)) = self.window_start() {
+                if !self.fifo.window && self.fifo.lcd_x >= start {
+                    self.fifo.window = true;
+                    self.fifo.bg.clear();
+                    self.fifo.reset_fetcher();
+                    self.fifo.discard = cut;
+                    self.window_drawn = true
                 }
+            }
 
-                Mode::VRam => {
-                    if cycles >= VRAM_CYCLES {
-                        self.mode_cycles -= VRAM_CYCLES;
-                        self.lcdstat.mode = Mode::HBlank
+            if self.lcdc.obj_display_enable {
+                let lcd_x = self.fifo.lcd_x as u16;
+                let oam = &self.oam;
+                let hit = self.fifo.sprites
+                    .iter()
+                    .position(|&sprite| oam[sprite as usize * 4 + 1] as u16 <= lcd_x + 8);
+                if let Some(i) = hit {
+                    let sprite = self.fifo.sprites.remove(i);
+                    self.fifo.fetched_sprites += 1;
+                    if self.fifo.fetched_sprites > MAX_SPRITES_PER_LINE {
+                        // Sprites past the hardware limit are fetched without a stall
+                        self.fetch_sprite(sprite)
+                    } else {
+                        self.fifo.pending_sprite = sprite;
+                        self.fifo.sprite_stall = SPRITE_FETCH_DOTS;
+                        return;
                     }
                 }
             }
-        } else {
-            if self.mode_cycles >= CLKS_SCREEN_REFRESH {
-                self.mode_cycles -= CLKS_SCREEN_REFRESH
+        }
+
+        self.fetcher_dot();
+
+        if let Some(bg) = self.fifo.bg.pop_front() {
+            let obj = self.fifo.obj.pop_front();
+            if self.fifo.discard > 0 {
+                self.fifo.discard -= 1;
+                return;
+            }
+
+            self.output_pixel(bg, obj);
+
+            self.fifo.lcd_x += 1;
+            if self.fifo.lcd_x as usize == DISPLAY_WIDTH {
+                self.fifo.active = false
             }
         }
-        interrupt
     }
 
-    pub fn oam_dma_transfer(&mut self, oam: Box<[u8]>) {
-        self.oam = oam
+    fn fetcher_dot(&mut self) {
+        if self.fifo.step == FetcherStep::Push {
+            let x_flip = (self.fifo.tile_attributes & 0x20) != 0;
+            self.fifo.push_bg_row(x_flip);
+            return;
+        }
+
+        if !self.fifo.tick_fetcher() {
+            return;
+        }
+
+        match self.fifo.step {
+            FetcherStep::TileNumber => {
+                let (map_select, x, y) = if self.fifo.window {
+                    (self.lcdc.window_tile_map_display_select,
+                     self.fifo.fetcher_x,
+                     self.window_line)
+                } else {
+                    (self.lcdc.bg_tile_map_display_select,
+                     (self.scx / 8).wrapping_add(self.fifo.fetcher_x),
+                     self.ly.wrapping_add(self.scy))
+                };
+                let map = if map_select { 0x9c00 } else { 0x9800 };
+                let tile_address = map + (y / 8) as u16 * 32 + (x & 0x1f) as u16;
+
+                self.fifo.tile_num = self.read_vram(0, tile_address);
+                // CGB stores the tile attributes in VRAM bank 1
+                self.fifo.tile_attributes = if self.cgb_mode() {
+                    self.read_vram(1, tile_address)
+                } else {
+                    0
+                };
+                self.fifo.tile_line = if (self.fifo.tile_attributes & 0x40) != 0 {
+                    7 - (y % 8) as u16
+                } else {
+                    (y % 8) as u16
+                };
+            }
+            FetcherStep::DataLow => {
+                let (bank, address) = self.fifo_tile_address();
+                self.fifo.data_low = self.read_vram(bank, address)
+            }
+            FetcherStep::DataHigh => {
+                let (bank, address) = self.fifo_tile_address();
+                self.fifo.data_high = self.read_vram(bank, address + 1)
+            }
+            FetcherStep::Push => {}
+        }
+        self.fifo.next_step()
     }
 
-    fn vbk_offset(&self) -> u16 {
-        (self.vbk | 0x01) as u16 * 0x2000
+    fn fifo_tile_address(&self) -> (u16, u16) {
+        let tile_num = self.fifo.tile_num;
+        let tile_location = if self.lcdc.bg_window_tile_data_select {
+            0x8000 + tile_num as u16 * 16
+        } else {
+            (0x9000 + (tile_num as i8 as i32) * 16) as u16
+        };
+        let bank = ((self.fifo.tile_attributes >> 3) & 0b1) as u16;
+        (bank, tile_location + self.fifo.tile_line * 2)
+    }
+
+    fn fetch_sprite(&mut self, sprite: u8) {
+        let x_pos = self.oam[sprite as usize * 4 + 1];
+        let attributes = self.oam[sprite as usize * 4 + 3];
+
+        let colors = self.sprite_row(sprite);
+        let mut pixels = [ObjPixel { color: 0, attributes, oam_index: sprite }; 8];
+        for (pixel, &color) in pixels.iter_mut().zip(colors.iter()) {
+            pixel.color = color
+        }
+
+        // Sprites partially off the left edge of the screen lose their first pixels
+        let skip = (self.fifo.lcd_x as usize + 8).saturating_sub(x_pos as usize).min(8);
+        let oam_priority = self.oam_priority();
+        self.fifo.merge_obj_row(pixels, skip, oam_priority)
+    }
+
+    // Color numbers of the sprite's pixels on the current line, from left to right
+    fn sprite_row(&self, sprite: u8) -> [u8; 8] {
+        let index = sprite as usize * 4;
+        let y_pos = self.oam[index];
+        let attributes = self.oam[index + 3];
+        let y_flip = (attributes & 0x40) != 0;
+        let x_flip = (attributes & 0x20) != 0;
+
+        // 8x16 sprites ignore bit 0 of the tile number
+        let (height, tile_num) = if self.lcdc.obj_size {
+            (16, self.oam[index + 2] & 0xfe)
+        } else {
+            (8, self.oam[index + 2])
+        };
+        let line = (self.ly.wrapping_add(16).wrapping_sub(y_pos) % height) as u16;
+        let line = if y_flip { height as u16 - 1 - line } else { line };
+
+        let bank = if self.cgb_mode() {
+            ((attributes >> 3) & 0b1) as u16
+        } else {
+            0
+        };
+        let address = 0x8000 + tile_num as u16 * 16 + line * 2;
+        let data1 = self.read_vram(bank, address);
+        let data2 = self.read_vram(bank, address + 1);
+
+        let mut colors = [0; 8];
+        for (i, color) in colors.iter_mut().enumerate() {
+            let bit = if x_flip { i } else { 7 - i };
+            *color = (((data2 >> bit) & 0b1) << 1) | ((data1 >> bit) & 0b1)
+        }
+        colors
+    }
+
+    // CGB sprites are prioritized by OAM index, DMG sprites by X coordinate first
+    fn oam_priority(&self) -> bool {
+        self.cgb_mode() && self.opri == 0
+    }
+
+    fn output_pixel(&mut self, bg: BgPixel, obj: Option<ObjPixel>) {
+        let x = self.fifo.lcd_x as u32;
+        let y = self.ly as u32;
+
+        // On CGB, LCDC bit 0 only removes the background priority over sprites
+        if self.cgb_mode() || self.lcdc.bg_display {
+            let palette = self.bg_palette(bg.attributes);
+            let color = self.get_color(bg.color, palette);
+            self.bg_line[x as usize] = bg.color;
+            self.bg_priority[x as usize] = (bg.attributes & 0x80) != 0;
+            self.set_pixel(x, y, color)
+        } else {
+            let color = self.blank_color();
+            self.set_pixel(x, y, color)
+        }
+
+        if let Some(obj) = obj {
+            if obj.color != 0 && self.lcdc.obj_display_enable {
+                let palette = self.obj_palette(obj.attributes);
+                let color = self.get_color(obj.color, palette);
+                self.set_sprite_pixel(x, y, (obj.attributes & 0x80) != 0, color)
+            }
+        }
     }
 
     fn draw_scanline(&mut self) {
-        if self.lcdc.bg_display {
+        self.bg_line = [0; DISPLAY_WIDTH];
+        self.bg_priority = [false; DISPLAY_WIDTH];
+
+        // On CGB, LCDC bit 0 only removes the background priority over sprites
+        if self.cgb_mode() || self.lcdc.bg_display {
             self.render_tiles()
+        } else {
+            self.clear_line()
         }
 
         if self.lcdc.obj_display_enable {
@@ -413,16 +1315,15 @@ impl Ppu {
     }
 
     fn render_tiles(&mut self) {
-
-
         let scanline = self.ly;
 
         let scroll_y = self.scy;
         let scroll_x = self.scx;
-        let window_y = self.window_y;
-        let window_x = self.window_x.wrapping_sub(7);
 
-        let using_window = self.lcdc.window_display_enable && window_y <= scanline;
+        let window_start = self.window_start();
+        if window_start.is_some() {
+            self.window_drawn = true
+        }
 
         let (tile_data, unsigned): (u16, bool) = if self.lcdc.bg_window_tile_data_select {
             (0x8000, true)
@@ -430,44 +1331,45 @@ impl Ppu {
             (0x8800, false)
         };
 
-        let background_mem = if using_window {
-            if self.lcdc.window_tile_map_display_select {
-                0x9c00
-            } else {
-                0x9800
-            }
+        let window_mem = if self.lcdc.window_tile_map_display_select {
+            0x9c00
         } else {
-            if self.lcdc.bg_tile_map_display_select {
-                0x9c00
-            } else {
-                0x9800
-            }
+            0x9800
         };
-
-        let y_pos = if using_window {
-            scanline.wrapping_sub(window_y)
+        let background_mem = if self.lcdc.bg_tile_map_display_select {
+            0x9c00
         } else {
-            scroll_y.wrapping_add(scanline)
+            0x9800
         };
 
-        let tile_row: u16 = (y_pos / 8) as u16 * 32;
-
         for pixel in 0..160 {
             let pixel = pixel as u8;
-            let x_pos = if using_window && pixel >= window_x {
-                pixel.wrapping_sub(window_x)
-            } else {
-                pixel.wrapping_add(scroll_x)
+            let (map, x_pos, y_pos) = match window_start {
+                Some((start, cut)) if pixel >= start => {
+                    (window_mem, pixel - start + cut, self.window_line)
+                }
+                _ => (background_mem, pixel.wrapping_add(scroll_x), scroll_y.wrapping_add(scanline)),
             };
 
+            let tile_row = (y_pos / 8) as u16 * 32;
             let tile_col = (x_pos / 8) as u16;
 
-            let tile_address = background_mem + tile_row + tile_col;
+            let tile_address = map + tile_row + tile_col;
+
+            // CGB stores the tile attributes in VRAM bank 1
+            let attributes = if self.cgb_mode() {
+                self.read_vram(1, tile_address)
+            } else {
+                0
+            };
+            let tile_bank = ((attributes >> 3) & 0b1) as u16;
+            let x_flip = (attributes & 0x20) != 0;
+            let y_flip = (attributes & 0x40) != 0;
 
             let tile_num: i16 = if unsigned {
-                self.read(tile_address) as u16 as i16
+                self.read_vram(0, tile_address) as u16 as i16
             } else {
-                self.read(tile_address) as i8 as i16
+                self.read_vram(0, tile_address) as i8 as i16
             };
 
             let tile_location: u16 = if unsigned {
@@ -476,94 +1378,101 @@ impl Ppu {
                 tile_data + ((tile_num + 128) * 16) as u16
             };
 
-            let line = (y_pos as u16 % 8) * 2;
-            let data1 = self.read(tile_location + line);
-            let data2 = self.read(tile_location + line + 1);
+            let line = if y_flip {
+                7 - (y_pos as u16 % 8)
+            } else {
+                y_pos as u16 % 8
+            };
+            let line = line * 2;
+            let data1 = self.read_vram(tile_bank, tile_location + line);
+            let data2 = self.read_vram(tile_bank, tile_location + line + 1);
 
-            let color_bit = ((x_pos as i32 % 8) - 7) * -1;
+            let color_bit = if x_flip {
+                x_pos % 8
+            } else {
+                7 - (x_pos % 8)
+            };
 
             let color_num = ((data2 >> color_bit) & 0b1) << 1;
             let color_num = color_num | ((data1 >> color_bit) & 0b1);
 
-            let color = self.get_color(color_num, self.bgp);
+            let palette = self.bg_palette(attributes);
+            let color = self.get_color(color_num, palette);
+            self.bg_line[pixel as usize] = color_num;
+            self.bg_priority[pixel as usize] = (attributes & 0x80) != 0;
             self.set_pixel(pixel as u32, scanline as u32, color)
 
         }
     }
 
     fn render_sprites(&mut self) {
+        let mut sprites = self.line_sprites.clone();
+        if !self.oam_priority() {
+            // Lower X wins, the sort is stable so ties go to the lower OAM index
+            let oam = &self.oam;
+            sprites.sort_by_key(|&sprite| oam[sprite as usize * 4 + 1])
+        }
 
-        let use_8x16 = self.lcdc.obj_size;
-
-        for sprite in 0..40 {
-            let index: u8 = sprite * 4;
-
-            let y_pos = self.oam[index as usize].wrapping_sub(16);
-            let x_pos = self.oam[(index + 1) as usize].wrapping_sub(8);
-            let tile_location = self.oam[(index + 2) as usize] as u16;
-            let attributes = self.oam[(index + 3) as usize];
-            let y_flip = (attributes & 0x40) != 0;
-            let x_flip = (attributes & 0x20) != 0;
-            let scanline = self.ly;
-
-            let y_size = if use_8x16 { 16 } else { 8 };
+        // Only the highest priority opaque sprite pixel is mixed with the background
+        let mut obj_line: [Option<(u8, u8)>; DISPLAY_WIDTH] = [None; DISPLAY_WIDTH];
+        for sprite in sprites {
+            let x_pos = self.oam[sprite as usize * 4 + 1] as usize;
+            let attributes = self.oam[sprite as usize * 4 + 3];
+            for (i, &color) in self.sprite_row(sprite).iter().enumerate() {
+                let x = x_pos + i;
+                if color == 0 || !(8..DISPLAY_WIDTH + 8).contains(&x) {
+                    continue;
+                }
+                if obj_line[x - 8].is_none() {
+                    obj_line[x - 8] = Some((color, attributes))
+                }
+            }
+        }
 
-            if scanline >= y_pos && scanline < (y_pos.wrapping_add(y_size)) {
-                let line: i32 = scanline as i32 - y_pos as i32;
+        let scanline = self.ly as u32;
+        for (x, pixel) in obj_line.iter().enumerate() {
+            if let Some((color_num, attributes)) = *pixel {
+                let palette = self.obj_palette(attributes);
+                let color = self.get_color(color_num, palette);
+                let obj_to_bg_pri = (attributes & 0x80) != 0;
+                self.set_sprite_pixel(x as u32, scanline, obj_to_bg_pri, color)
+            }
+        }
+    }
 
-                let line = if y_flip {
-                    (line - y_size as i32) * -1
+    fn get_color(&self, color_id: u8, palette: PaletteSelect) -> Color {
+        match palette {
+            PaletteSelect::DmgBackground(palette_num) => {
+                let shade = Ppu::get_shade(color_id, palette_num);
+                Color::from_rgb(self.dmg_palette.bg[shade as usize])
+            }
+            PaletteSelect::DmgObject(obp_num, palette_num) => {
+                let shade = Ppu::get_shade(color_id, palette_num);
+                let colors = if obp_num == 0 {
+                    &self.dmg_palette.obj_0
                 } else {
-                    line
+                    &self.dmg_palette.obj_1
                 };
-
-                let line = line * 2;
-
-                let data_address = 0x8000 + (tile_location * 16) + line as u16;
-
-                let data1 = self.read(data_address);
-                let data2 = self.read(data_address + 1);
-
-                for tile_pixel in (0..8).rev() {
-                    let color_bit = tile_pixel as i32;
-                    let color_bit = if x_flip {
-                        (color_bit - 7) * -1
-                    } else {
-                        color_bit
-                    };
-
-                    let color_num = ((data2 >> color_bit) & 0b1) << 1;
-                    let color_num = color_num | ((data1 >> color_bit) & 0b1);
-
-                    let palette_num = if (attributes & 0x10) != 0 {
-                        self.obp_1
-                    } else {
-                        self.obp_0
-                    };
-
-                    if color_num == 0 {
-                        continue;
-                    }
-                    let color = self.get_color(color_num, palette_num);
-
-                    let x_pix = (0 as u8).wrapping_sub(tile_pixel as u8);
-                    let x_pix = x_pix.wrapping_add(7);
-
-                    let pixel = x_pos.wrapping_add(x_pix);
-
-                    if scanline > 143 || pixel > 159 {
-                        continue;
-                    }
-
-                    let obj_to_bg_pri = (attributes & 0x80) != 0;
-                    self.set_sprite_pixel(pixel as u32, scanline as u32, obj_to_bg_pri, color)
-                }
+                Color::from_rgb(colors[shade as usize])
+            }
+            PaletteSelect::CgbBackground(palette_num) => {
+                self.bg_palette_ram.get_color(palette_num, color_id)
+            }
+            PaletteSelect::CgbObject(palette_num) => {
+                self.obj_palette_ram.get_color(palette_num, color_id)
+            }
+            PaletteSelect::CompatBackground(palette_num) => {
+                let shade = Ppu::get_shade(color_id, palette_num);
+                self.bg_palette_ram.get_color(0, shade)
+            }
+            PaletteSelect::CompatObject(obp_num, palette_num) => {
+                let shade = Ppu::get_shade(color_id, palette_num);
+                self.obj_palette_ram.get_color(obp_num, shade)
             }
         }
     }
 
-    fn get_color(&self, color_id: u8, palette_num: u8) -> Color {
-
+    fn get_shade(color_id: u8, palette_num: u8) -> u8 {
         let (hi, lo) = match color_id {
             0 => (1, 0),
             1 => (3, 2),
@@ -573,32 +1482,49 @@ impl Ppu {
         };
 
         let color = ((palette_num >> hi) & 0b1) << 1;
-        let color = color | ((palette_num >> lo) & 0b1);
-
-        match color {
-            0 => WHITE,
-            1 => LIGHT_GRAY,
-            2 => DARK_GRAY,
-            3 => BLACK,
-            _ => panic!("Invalid color: 0x{:x}", color),
-        }
+        color | ((palette_num >> lo) & 0b1)
     }
 
     fn set_sprite_pixel(&mut self, x: u32, y: u32, pri: bool, color: Color) {
-        let offset = ((y * 160) + x) as usize;
-        let pixel = Color {
-            a: (self.framebuffer[offset] >> 24) as u8,
-            r: (self.framebuffer[offset] >> 16) as u8,
-            g: (self.framebuffer[offset] >> 8) as u8,
-            b: self.framebuffer[offset] as u8,
+        let bg_opaque = self.bg_line[x as usize] != 0;
+        let bg_over_obj = if self.cgb_mode() {
+            self.lcdc.bg_display && bg_opaque && (pri || self.bg_priority[x as usize])
+        } else {
+            bg_opaque && pri
         };
 
-        if pixel != WHITE && pri {
-            return;
-        } else {
+        if !bg_over_obj {
             self.set_pixel(x, y, color)
         }
+    }
+
+    // Color shown where the background is disabled
+    fn blank_color(&self) -> Color {
+        match self.gameboy_type {
+            GameboyType::Cgb => self.bg_palette_ram.get_color(0, 0),
+            GameboyType::Dmg => Color::from_rgb(self.dmg_palette.bg[0]),
+        }
+    }
+
+    // The LCD shows white while it is off
+    fn clear_framebuffer(&mut self) {
+        let color = match self.gameboy_type {
+            GameboyType::Cgb => Color::from_rgb(0xffffff),
+            GameboyType::Dmg => Color::from_rgb(self.dmg_palette.bg[0]),
+        };
+        for y in 0..DISPLAY_HEIGHT as u32 {
+            for x in 0..DISPLAY_WIDTH as u32 {
+                self.set_pixel(x, y, color)
+            }
+        }
+    }
 
+    fn clear_line(&mut self) {
+        let scanline = self.ly as u32;
+        let color = self.blank_color();
+        for x in 0..DISPLAY_WIDTH as u32 {
+            self.set_pixel(x, scanline, color)
+        }
     }
 
     fn set_pixel(&mut self, x: u32, y: u32, color: Color) {
@@ -611,3 +1537,77 @@ impl Ppu {
         self.framebuffer[offset] = c
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reload(ppu: &Ppu) -> Result<(), StateError> {
+        let mut writer = StateWriter::new(0, 0);
+        ppu.save_state(&mut writer);
+        let bytes = writer.into_bytes();
+        let mut reader = StateReader::new(&bytes, 0, 0).unwrap();
+        Ppu::new(GameboyType::Dmg).load_state(&mut reader)
+    }
+
+    fn ppu_at(mode: Mode, ly: u8, line_cycles: u32) -> Ppu {
+        let mut ppu = Ppu::new(GameboyType::Dmg);
+        ppu.lcdstat.mode = mode;
+        ppu.ly = ly;
+        ppu.line_cycles = line_cycles;
+        ppu.vram_cycles = VRAM_CYCLES;
+        ppu
+    }
+
+    #[test]
+    fn running_ppu_has_valid_line_timing() {
+        for &renderer in [Renderer::Scanline, Renderer::PixelFifo].iter() {
+            let mut ppu = Ppu::new(GameboyType::Dmg);
+            ppu.set_renderer(renderer);
+            ppu.write(0xff40, 0x93);
+            for _ in 0..2 * CLKS_SCREEN_REFRESH / 4 {
+                ppu.cycle_flush(4);
+                assert!(ppu.valid_line_timing(),
+                        "{:?} ly {} line cycles {}",
+                        ppu.lcdstat.mode,
+                        ppu.ly,
+                        ppu.line_cycles);
+            }
+            assert_eq!(reload(&ppu), Ok(()));
+        }
+    }
+
+    #[test]
+    fn inconsistent_line_timing_is_rejected() {
+        assert_eq!(reload(&ppu_at(Mode::Oam, 10, 100)), Err(StateError::InvalidFormat));
+        assert_eq!(reload(&ppu_at(Mode::Oam, 150, 0)), Err(StateError::InvalidFormat));
+        assert_eq!(reload(&ppu_at(Mode::VRam, 10, 40)), Err(StateError::InvalidFormat));
+        assert_eq!(reload(&ppu_at(Mode::VRam, 10, OAM_CYCLES + VRAM_CYCLES)),
+                   Err(StateError::InvalidFormat));
+        assert_eq!(reload(&ppu_at(Mode::HBlank, 10, LINE_CYCLES)),
+                   Err(StateError::InvalidFormat));
+        assert_eq!(reload(&ppu_at(Mode::VBlank, 153, LY_153_CYCLES)),
+                   Err(StateError::InvalidFormat));
+        assert_eq!(reload(&ppu_at(Mode::VBlank, 0, 0)), Err(StateError::InvalidFormat));
+        assert_eq!(reload(&ppu_at(Mode::VBlank, 154, 0)), Err(StateError::InvalidFormat));
+
+        assert_eq!(reload(&ppu_at(Mode::VRam, 10, OAM_CYCLES)), Ok(()));
+        assert_eq!(reload(&ppu_at(Mode::VBlank, 153, 0)), Ok(()));
+        assert_eq!(reload(&ppu_at(Mode::VBlank, 0, 100)), Ok(()));
+    }
+
+    #[test]
+    fn hdma_writes_vram_during_pixel_transfer() {
+        let mut ppu = Ppu::new(GameboyType::Cgb);
+        ppu.write(0xff40, 0x93);
+        ppu.write(0xff4f, 1);
+        ppu.lcdstat.mode = Mode::VRam;
+
+        ppu.write(0x8010, 0x12);
+        assert_eq!(ppu.read_vram(1, 0x8010), 0);
+
+        ppu.hdma_write(0x0010, 0x34);
+        assert_eq!(ppu.read_vram(1, 0x8010), 0x34);
+        assert_eq!(ppu.read_vram(0, 0x8010), 0);
+    }
+}
@@ -1,4 +1,5 @@
 use super::GameboyType;
+use super::save_state::{StateWriter,StateReader,StateError};
 use std::fmt;
 use std::fmt::Debug;
 use std::default::Default;
@@ -24,6 +25,7 @@ pub enum Reg16 {
     SP,
 }
 
+#[derive(Clone)]
 pub struct Registers {
     pub a: u8,
     pub b: u8,
@@ -69,6 +71,34 @@ impl Registers {
         }
     }
 
+    // The state the boot ROM starts from
+    pub fn power_on() -> Registers {
+        Registers {
+            sp: 0,
+            pc: 0,
+            ..Default::default()
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.read_u16(Reg16::AF));
+        state.write_u16(self.read_u16(Reg16::BC));
+        state.write_u16(self.read_u16(Reg16::DE));
+        state.write_u16(self.read_u16(Reg16::HL));
+        state.write_u16(self.sp);
+        state.write_u16(self.pc)
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.write_u16(Reg16::AF, state.read_u16()?);
+        self.write_u16(Reg16::BC, state.read_u16()?);
+        self.write_u16(Reg16::DE, state.read_u16()?);
+        self.write_u16(Reg16::HL, state.read_u16()?);
+        self.sp = state.read_u16()?;
+        self.pc = state.read_u16()?;
+        Ok(())
+    }
+
     #[inline(always)]
     pub fn read_u8(&self, reg: Reg8) -> u8 {
         use self::Reg8::*;
@@ -0,0 +1,249 @@
+use std::fmt;
+
+// Save states start with a header identifying the format version, the model and the ROM.
+// After it every component writes its fields in a fixed order, multi-byte values are
+// little-endian. Bump the version whenever the layout changes.
+const MAGIC: &[u8; 4] = b"GBCS";
+pub const SAVE_STATE_VERSION: u16 = 2;
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum StateError {
+    // Not a save state, or the data is truncated or corrupt
+    InvalidFormat,
+    UnsupportedVersion(u16),
+    // The state was made with a different ROM
+    RomMismatch,
+    // The state was made on a different model (DMG/CGB)
+    ModelMismatch,
+    // The state was made while the boot ROM was running, but no boot ROM is loaded
+    BootRomMissing,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StateError::InvalidFormat => write!(f, "not a valid save state"),
+            StateError::UnsupportedVersion(version) => {
+                write!(f, "unsupported save state version {}", version)
+            }
+            StateError::RomMismatch => write!(f, "save state is for a different ROM"),
+            StateError::ModelMismatch => write!(f, "save state is for a different model"),
+            StateError::BootRomMissing => write!(f, "save state needs a boot ROM"),
+        }
+    }
+}
+
+pub struct StateWriter {
+    bytes: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new(model: u8, rom_checksum: u32) -> StateWriter {
+        let mut writer = StateWriter { bytes: Vec::new() };
+        writer.write_bytes(MAGIC);
+        writer.write_u16(SAVE_STATE_VERSION);
+        writer.write_u8(model);
+        writer.write_u32(rom_checksum);
+        writer
+    }
+
+    pub fn write_u8(&mut self, val: u8) {
+        self.bytes.push(val)
+    }
+
+    pub fn write_bool(&mut self, val: bool) {
+        self.write_u8(val as u8)
+    }
+
+    pub fn write_u16(&mut self, val: u16) {
+        self.write_u8(val as u8);
+        self.write_u8((val >> 8) as u8)
+    }
+
+    pub fn write_u32(&mut self, val: u32) {
+        self.write_u16(val as u16);
+        self.write_u16((val >> 16) as u16)
+    }
+
+    pub fn write_f32(&mut self, val: f32) {
+        self.write_u32(val.to_bits())
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes)
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+pub struct StateReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    // Checks the header against the running console
+    pub fn new(bytes: &'a [u8], model: u8, rom_checksum: u32) -> Result<StateReader<'a>, StateError> {
+        let mut reader = StateReader {
+            bytes,
+            pos: 0,
+        };
+
+        let mut magic = [0; 4];
+        reader.read_bytes(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(StateError::InvalidFormat);
+        }
+
+        let version = reader.read_u16()?;
+        if version != SAVE_STATE_VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+
+        if reader.read_u8()? != model {
+            return Err(StateError::ModelMismatch);
+        }
+        if reader.read_u32()? != rom_checksum {
+            return Err(StateError::RomMismatch);
+        }
+
+        Ok(reader)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        match self.bytes.get(self.pos) {
+            Some(&val) => {
+                self.pos += 1;
+                Ok(val)
+            }
+            None => Err(StateError::InvalidFormat),
+        }
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::InvalidFormat),
+        }
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        let low = self.read_u8()? as u16;
+        let high = self.read_u8()? as u16;
+        Ok((high << 8) | low)
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        let low = self.read_u16()? as u32;
+        let high = self.read_u16()? as u32;
+        Ok((high << 16) | low)
+    }
+
+    pub fn read_f32(&mut self) -> Result<f32, StateError> {
+        Ok(f32::from_bits(self.read_u32()?))
+    }
+
+    // Fills bytes completely, the length is known from the component's own layout
+    pub fn read_bytes(&mut self, bytes: &mut [u8]) -> Result<(), StateError> {
+        let end = self.pos + bytes.len();
+        if end > self.bytes.len() {
+            return Err(StateError::InvalidFormat);
+        }
+        bytes.copy_from_slice(&self.bytes[self.pos..end]);
+        self.pos = end;
+        Ok(())
+    }
+
+    // Every byte of the state must have been read
+    pub fn finish(self) -> Result<(), StateError> {
+        if self.pos == self.bytes.len() {
+            Ok(())
+        } else {
+            Err(StateError::InvalidFormat)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODEL: u8 = 1;
+    const ROM_CHECKSUM: u32 = 0x1234_5678;
+
+    fn test_state() -> Vec<u8> {
+        let mut writer = StateWriter::new(MODEL, ROM_CHECKSUM);
+        writer.write_u8(0xab);
+        writer.write_bool(true);
+        writer.write_u16(0xbeef);
+        writer.write_u32(0xdead_beef);
+        writer.write_f32(-0.5);
+        writer.write_bytes(&[1, 2, 3]);
+        writer.into_bytes()
+    }
+
+    fn read_test_state(bytes: &[u8]) -> Result<(), StateError> {
+        let mut reader = StateReader::new(bytes, MODEL, ROM_CHECKSUM)?;
+        assert_eq!(reader.read_u8()?, 0xab);
+        assert!(reader.read_bool()?);
+        assert_eq!(reader.read_u16()?, 0xbeef);
+        assert_eq!(reader.read_u32()?, 0xdead_beef);
+        assert_eq!(reader.read_f32()?, -0.5);
+        let mut bytes = [0; 3];
+        reader.read_bytes(&mut bytes)?;
+        assert_eq!(bytes, [1, 2, 3]);
+        reader.finish()
+    }
+
+    #[test]
+    fn roundtrip() {
+        assert_eq!(read_test_state(&test_state()), Ok(()));
+    }
+
+    #[test]
+    fn truncated_state_is_rejected() {
+        let state = test_state();
+        for len in 0..state.len() {
+            assert_eq!(read_test_state(&state[..len]), Err(StateError::InvalidFormat));
+        }
+    }
+
+    #[test]
+    fn trailing_bytes_are_rejected() {
+        let mut state = test_state();
+        state.push(0);
+        assert_eq!(read_test_state(&state), Err(StateError::InvalidFormat));
+    }
+
+    #[test]
+    fn invalid_bool_is_rejected() {
+        let mut writer = StateWriter::new(MODEL, ROM_CHECKSUM);
+        writer.write_u8(2);
+        let bytes = writer.into_bytes();
+        let mut reader = StateReader::new(&bytes, MODEL, ROM_CHECKSUM).unwrap();
+        assert_eq!(reader.read_bool(), Err(StateError::InvalidFormat));
+    }
+
+    #[test]
+    fn header_is_checked() {
+        let state = StateWriter::new(MODEL, ROM_CHECKSUM).into_bytes();
+
+        let mut bad_magic = state.clone();
+        bad_magic[0] ^= 0xff;
+        assert_eq!(StateReader::new(&bad_magic, MODEL, ROM_CHECKSUM).err(),
+                   Some(StateError::InvalidFormat));
+
+        let mut bad_version = state.clone();
+        bad_version[4] = bad_version[4].wrapping_add(1);
+        assert_eq!(StateReader::new(&bad_version, MODEL, ROM_CHECKSUM).err(),
+                   Some(StateError::UnsupportedVersion(SAVE_STATE_VERSION + 1)));
+
+        assert_eq!(StateReader::new(&state, MODEL + 1, ROM_CHECKSUM).err(),
+                   Some(StateError::ModelMismatch));
+        assert_eq!(StateReader::new(&state, MODEL, ROM_CHECKSUM + 1).err(),
+                   Some(StateError::RomMismatch));
+    }
+}
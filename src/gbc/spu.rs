@@ -1,17 +1,974 @@
+use super::CpuClock;
+use super::save_state::{StateWriter,StateReader,StateError};
 
-#[derive(Debug)]
-pub struct Spu;
+const WAVE_RAM_SIZE: usize = 16;
+
+const DUTY_TABLE: [[u8; 8]; 4] = [[0, 0, 0, 0, 0, 0, 0, 1],
+                                  [1, 0, 0, 0, 0, 0, 0, 1],
+                                  [1, 0, 0, 0, 0, 1, 1, 1],
+                                  [0, 1, 1, 1, 1, 1, 1, 0]];
+
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+// Per-cycle charge factor of the output high-pass filter capacitor on DMG
+const HIGH_PASS_CHARGE_FACTOR: f32 = 0.999958;
+
+pub trait AudioSink {
+    // The host output rate in Hz. Samples are produced at this rate
+    fn sample_rate(&self) -> u32;
+
+    // Interleaved stereo samples, left channel first
+    fn samples_available(&mut self, samples: &[i16]);
+}
+
+#[derive(Debug,Clone)]
+struct LengthCounter {
+    enabled: bool,
+    counter: u16,
+    max: u16,
+}
+
+impl LengthCounter {
+    fn new(max: u16) -> LengthCounter {
+        LengthCounter {
+            enabled: false,
+            counter: 0,
+            max,
+        }
+    }
+
+    fn load(&mut self, val: u8) {
+        self.counter = self.max - val as u16
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_u16(self.counter)
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.counter = state.read_u16()?.min(self.max);
+        Ok(())
+    }
+
+    // Returns true when the counter expires and the channel should be disabled
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            self.counter == 0
+        } else {
+            false
+        }
+    }
+
+    // Handles the NRx4 write, including the extra clock that happens when length is enabled
+    // during the first half of a length period. Returns true if the channel should be disabled.
+    fn write_control(&mut self, val: u8, trigger: bool, frame_step: u8) -> bool {
+        let was_enabled = self.enabled;
+        self.enabled = (val & 0x40) != 0;
+
+        let extra_clock = (frame_step & 1) == 1;
+        let mut disable = false;
+
+        if !was_enabled && self.enabled && extra_clock {
+            disable = self.clock() && !trigger;
+        }
+
+        if trigger && self.counter == 0 {
+            self.counter = self.max;
+            if self.enabled && extra_clock {
+                self.counter -= 1
+            }
+        }
+
+        disable
+    }
+}
+
+#[derive(Debug,Clone)]
+struct Envelope {
+    initial_volume: u8,
+    increase: bool,
+    period: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn new() -> Envelope {
+        Envelope {
+            initial_volume: 0,
+            increase: false,
+            period: 0,
+            volume: 0,
+            timer: 0,
+        }
+    }
+
+    fn get_flags(&self) -> u8 {
+        (self.initial_volume << 4) | if self.increase { 0b1000 } else { 0 } | self.period
+    }
+
+    fn set_flags(&mut self, flags: u8) {
+        self.initial_volume = flags >> 4;
+        self.increase = (flags & 0b1000) != 0;
+        self.period = flags & 0b111
+    }
+
+    fn dac_enabled(&self) -> bool {
+        self.initial_volume != 0 || self.increase
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.get_flags());
+        state.write_u8(self.volume);
+        state.write_u8(self.timer)
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let flags = state.read_u8()?;
+        self.set_flags(flags);
+        self.volume = state.read_u8()? & 0x0f;
+        self.timer = state.read_u8()?;
+        Ok(())
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial_volume;
+        self.timer = self.period
+    }
+
+    fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+
+        if self.timer > 0 {
+            self.timer -= 1
+        }
+
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increase && self.volume < 15 {
+                self.volume += 1
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1
+            }
+        }
+    }
+}
+
+#[derive(Debug,Clone)]
+struct Sweep {
+    period: u8,
+    negate: bool,
+    shift: u8,
+    timer: u8,
+    enabled: bool,
+    shadow_frequency: u16,
+    negate_used: bool,
+}
+
+impl Sweep {
+    fn new() -> Sweep {
+        Sweep {
+            period: 0,
+            negate: false,
+            shift: 0,
+            timer: 0,
+            enabled: false,
+            shadow_frequency: 0,
+            negate_used: false,
+        }
+    }
+
+    fn get_flags(&self) -> u8 {
+        0x80 | (self.period << 4) | if self.negate { 0b1000 } else { 0 } | self.shift
+    }
+
+    fn reload_timer(&mut self) {
+        self.timer = if self.period == 0 { 8 } else { self.period }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.get_flags());
+        state.write_u8(self.timer);
+        state.write_bool(self.enabled);
+        state.write_u16(self.shadow_frequency);
+        state.write_bool(self.negate_used)
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let flags = state.read_u8()?;
+        self.period = (flags >> 4) & 0b111;
+        self.negate = (flags & 0b1000) != 0;
+        self.shift = flags & 0b111;
+        self.timer = state.read_u8()?;
+        self.enabled = state.read_bool()?;
+        self.shadow_frequency = state.read_u16()?;
+        self.negate_used = state.read_bool()?;
+        Ok(())
+    }
+
+    fn calculate(&mut self) -> u16 {
+        let delta = self.shadow_frequency >> self.shift;
+        if self.negate {
+            self.negate_used = true;
+            self.shadow_frequency.wrapping_sub(delta)
+        } else {
+            self.shadow_frequency + delta
+        }
+    }
+}
+
+#[derive(Debug,Clone)]
+struct SquareChannel {
+    enabled: bool,
+    sweep: Option<Sweep>,
+    duty: u8,
+    duty_position: usize,
+    length: LengthCounter,
+    envelope: Envelope,
+    frequency: u16,
+    timer: u32,
+}
+
+impl SquareChannel {
+    fn new(with_sweep: bool) -> SquareChannel {
+        SquareChannel {
+            enabled: false,
+            sweep: if with_sweep { Some(Sweep::new()) } else { None },
+            duty: 0,
+            duty_position: 0,
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+            frequency: 0,
+            timer: 0,
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        if let Some(ref sweep) = self.sweep {
+            sweep.save_state(state)
+        }
+        state.write_u8(self.duty);
+        state.write_u8(self.duty_position as u8);
+        self.length.save_state(state);
+        self.envelope.save_state(state);
+        state.write_u16(self.frequency);
+        state.write_u32(self.timer)
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        if let Some(ref mut sweep) = self.sweep {
+            sweep.load_state(state)?
+        }
+        self.duty = state.read_u8()? & 0b11;
+        self.duty_position = (state.read_u8()? & 0b111) as usize;
+        self.length.load_state(state)?;
+        self.envelope.load_state(state)?;
+        self.frequency = state.read_u16()? & 0x7ff;
+        self.timer = state.read_u32()?;
+        Ok(())
+    }
+
+    fn read(&self, reg: u16) -> u8 {
+        match reg {
+            0 => self.sweep.as_ref().map_or(0xff, |s| s.get_flags()),
+            1 => (self.duty << 6) | 0x3f,
+            2 => self.envelope.get_flags(),
+            3 => 0xff,
+            4 => if self.length.enabled { 0xff } else { 0xbf },
+            _ => unreachable!(),
+        }
+    }
+
+    fn write(&mut self, reg: u16, val: u8, frame_step: u8) {
+        match reg {
+            0 => {
+                if let Some(ref mut sweep) = self.sweep {
+                    sweep.period = (val >> 4) & 0b111;
+                    sweep.negate = (val & 0b1000) != 0;
+                    sweep.shift = val & 0b111;
+                    // Clearing negate after it was used in a calculation disables the channel
+                    if !sweep.negate && sweep.negate_used {
+                        self.enabled = false
+                    }
+                }
+            }
+            1 => {
+                self.duty = val >> 6;
+                self.length.load(val & 0x3f)
+            }
+            2 => {
+                self.envelope.set_flags(val);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false
+                }
+            }
+            3 => self.frequency = (self.frequency & 0x700) | val as u16,
+            4 => {
+                self.frequency = (self.frequency & 0xff) | ((val as u16 & 0b111) << 8);
+                let trigger = (val & 0x80) != 0;
+                if self.length.write_control(val, trigger, frame_step) {
+                    self.enabled = false
+                }
+                if trigger {
+                    self.trigger()
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.timer = (2048 - self.frequency as u32) * 4;
+        self.envelope.trigger();
+
+        let frequency = self.frequency;
+        let mut overflow = false;
+        if let Some(ref mut sweep) = self.sweep {
+            sweep.shadow_frequency = frequency;
+            sweep.reload_timer();
+            sweep.enabled = sweep.period != 0 || sweep.shift != 0;
+            sweep.negate_used = false;
+            if sweep.shift != 0 {
+                overflow = sweep.calculate() > 2047
+            }
+        }
+        if overflow {
+            self.enabled = false
+        }
+    }
+
+    fn step(&mut self, cycle_count: u32) {
+        let mut cycles = cycle_count;
+        while cycles > 0 {
+            if self.timer > cycles {
+                self.timer -= cycles;
+                return;
+            }
+            cycles -= self.timer;
+            self.timer = (2048 - self.frequency as u32) * 4;
+            self.duty_position = (self.duty_position + 1) & 0b111
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false
+        }
+    }
+
+    fn clock_sweep(&mut self) {
+        let mut new_frequency = None;
+        let mut overflow = false;
+
+        if let Some(ref mut sweep) = self.sweep {
+            if sweep.timer > 0 {
+                sweep.timer -= 1
+            }
+
+            if sweep.timer == 0 {
+                sweep.reload_timer();
+                if sweep.enabled && sweep.period != 0 {
+                    let frequency = sweep.calculate();
+                    if frequency > 2047 {
+                        overflow = true
+                    } else if sweep.shift != 0 {
+                        sweep.shadow_frequency = frequency;
+                        new_frequency = Some(frequency);
+                        overflow = sweep.calculate() > 2047
+                    }
+                }
+            }
+        }
+
+        if let Some(frequency) = new_frequency {
+            self.frequency = frequency
+        }
+        if overflow {
+            self.enabled = false
+        }
+    }
+
+    fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled {
+            DUTY_TABLE[self.duty as usize][self.duty_position] * self.envelope.volume
+        } else {
+            0
+        }
+    }
+}
+
+#[derive(Debug,Clone)]
+struct WaveChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    length: LengthCounter,
+    volume_code: u8,
+    frequency: u16,
+    timer: u32,
+    position: usize,
+    sample_buffer: u8,
+    wave_ram: [u8; WAVE_RAM_SIZE],
+}
+
+impl WaveChannel {
+    fn new() -> WaveChannel {
+        WaveChannel {
+            enabled: false,
+            dac_enabled: false,
+            length: LengthCounter::new(256),
+            volume_code: 0,
+            frequency: 0,
+            timer: 0,
+            position: 0,
+            sample_buffer: 0,
+            wave_ram: [0; WAVE_RAM_SIZE],
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_bool(self.dac_enabled);
+        self.length.save_state(state);
+        state.write_u8(self.volume_code);
+        state.write_u16(self.frequency);
+        state.write_u32(self.timer);
+        state.write_u8(self.position as u8);
+        state.write_u8(self.sample_buffer);
+        state.write_bytes(&self.wave_ram)
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.dac_enabled = state.read_bool()?;
+        self.length.load_state(state)?;
+        self.volume_code = state.read_u8()? & 0b11;
+        self.frequency = state.read_u16()? & 0x7ff;
+        self.timer = state.read_u32()?;
+        self.position = (state.read_u8()? as usize) % (WAVE_RAM_SIZE * 2);
+        self.sample_buffer = state.read_u8()?;
+        state.read_bytes(&mut self.wave_ram)
+    }
+
+    fn read(&self, reg: u16) -> u8 {
+        match reg {
+            0 => if self.dac_enabled { 0xff } else { 0x7f },
+            1 => 0xff,
+            2 => (self.volume_code << 5) | 0x9f,
+            3 => 0xff,
+            4 => if self.length.enabled { 0xff } else { 0xbf },
+            _ => unreachable!(),
+        }
+    }
+
+    fn write(&mut self, reg: u16, val: u8, frame_step: u8) {
+        match reg {
+            0 => {
+                self.dac_enabled = (val & 0x80) != 0;
+                if !self.dac_enabled {
+                    self.enabled = false
+                }
+            }
+            1 => self.length.load(val),
+            2 => self.volume_code = (val >> 5) & 0b11,
+            3 => self.frequency = (self.frequency & 0x700) | val as u16,
+            4 => {
+                self.frequency = (self.frequency & 0xff) | ((val as u16 & 0b111) << 8);
+                let trigger = (val & 0x80) != 0;
+                if self.length.write_control(val, trigger, frame_step) {
+                    self.enabled = false
+                }
+                if trigger {
+                    self.enabled = self.dac_enabled;
+                    self.timer = (2048 - self.frequency as u32) * 2;
+                    self.position = 0
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn step(&mut self, cycle_count: u32) {
+        let mut cycles = cycle_count;
+        while cycles > 0 {
+            if self.timer > cycles {
+                self.timer -= cycles;
+                return;
+            }
+            cycles -= self.timer;
+            self.timer = (2048 - self.frequency as u32) * 2;
+            self.position = (self.position + 1) & 0x1f;
+            let byte = self.wave_ram[self.position / 2];
+            self.sample_buffer = if (self.position & 1) == 0 {
+                byte >> 4
+            } else {
+                byte & 0x0f
+            }
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        match self.volume_code {
+            0 => 0,
+            1 => self.sample_buffer,
+            2 => self.sample_buffer >> 1,
+            3 => self.sample_buffer >> 2,
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[derive(Debug,Clone)]
+struct NoiseChannel {
+    enabled: bool,
+    length: LengthCounter,
+    envelope: Envelope,
+    clock_shift: u8,
+    width_mode: bool,
+    divisor_code: u8,
+    lfsr: u16,
+    timer: u32,
+}
+
+impl NoiseChannel {
+    fn new() -> NoiseChannel {
+        NoiseChannel {
+            enabled: false,
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+            clock_shift: 0,
+            width_mode: false,
+            divisor_code: 0,
+            lfsr: 0x7fff,
+            timer: 0,
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        self.length.save_state(state);
+        self.envelope.save_state(state);
+        state.write_u8(self.clock_shift);
+        state.write_bool(self.width_mode);
+        state.write_u8(self.divisor_code);
+        state.write_u16(self.lfsr);
+        state.write_u32(self.timer)
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.length.load_state(state)?;
+        self.envelope.load_state(state)?;
+        self.clock_shift = state.read_u8()? & 0x0f;
+        self.width_mode = state.read_bool()?;
+        self.divisor_code = state.read_u8()? & 0b111;
+        self.lfsr = state.read_u16()? & 0x7fff;
+        self.timer = state.read_u32()?;
+        Ok(())
+    }
+
+    fn read(&self, reg: u16) -> u8 {
+        match reg {
+            0 => 0xff,
+            1 => 0xff,
+            2 => self.envelope.get_flags(),
+            3 => {
+                (self.clock_shift << 4) | if self.width_mode { 0b1000 } else { 0 } |
+                self.divisor_code
+            }
+            4 => if self.length.enabled { 0xff } else { 0xbf },
+            _ => unreachable!(),
+        }
+    }
+
+    fn write(&mut self, reg: u16, val: u8, frame_step: u8) {
+        match reg {
+            0 => {}
+            1 => self.length.load(val & 0x3f),
+            2 => {
+                self.envelope.set_flags(val);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false
+                }
+            }
+            3 => {
+                self.clock_shift = val >> 4;
+                self.width_mode = (val & 0b1000) != 0;
+                self.divisor_code = val & 0b111
+            }
+            4 => {
+                let trigger = (val & 0x80) != 0;
+                if self.length.write_control(val, trigger, frame_step) {
+                    self.enabled = false
+                }
+                if trigger {
+                    self.enabled = self.envelope.dac_enabled();
+                    self.timer = self.period();
+                    self.envelope.trigger();
+                    self.lfsr = 0x7fff
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn period(&self) -> u32 {
+        NOISE_DIVISORS[self.divisor_code as usize] << self.clock_shift
+    }
+
+    fn step(&mut self, cycle_count: u32) {
+        let mut cycles = cycle_count;
+        while cycles > 0 {
+            if self.timer > cycles {
+                self.timer -= cycles;
+                return;
+            }
+            cycles -= self.timer;
+            self.timer = self.period();
+
+            let xor = (self.lfsr & 0b1) ^ ((self.lfsr >> 1) & 0b1);
+            self.lfsr = (self.lfsr >> 1) | (xor << 14);
+            if self.width_mode {
+                self.lfsr = (self.lfsr & !0x40) | (xor << 6)
+            }
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled && (self.lfsr & 0b1) == 0 {
+            self.envelope.volume
+        } else {
+            0
+        }
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct Spu {
+    enabled: bool,
+    square_1: SquareChannel,
+    square_2: SquareChannel,
+    wave: WaveChannel,
+    noise: NoiseChannel,
+    nr50: u8,
+    nr51: u8,
+    frame_step: u8,
+    sample_rate: u32,
+    sample_counter: u32,
+    high_pass_charge: f32,
+    capacitor_left: f32,
+    capacitor_right: f32,
+    samples: Vec<i16>,
+}
 
 impl Spu {
     pub fn new() -> Spu {
-        Spu {}
+        Spu {
+            enabled: false,
+            square_1: SquareChannel::new(true),
+            square_2: SquareChannel::new(false),
+            wave: WaveChannel::new(),
+            noise: NoiseChannel::new(),
+            nr50: 0,
+            nr51: 0,
+            frame_step: 0,
+            sample_rate: 0,
+            sample_counter: 0,
+            high_pass_charge: 0.0,
+            capacitor_left: 0.0,
+            capacitor_right: 0.0,
+            samples: Vec::new(),
+        }
+    }
+
+    // The rate may change between frames (e.g. for dynamic rate control), so the sample
+    // phase is kept across changes
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        if self.sample_rate == sample_rate {
+            return;
+        }
+        self.sample_rate = sample_rate;
+        if sample_rate != 0 {
+            let clock = CpuClock::Normal.value();
+            self.high_pass_charge = HIGH_PASS_CHARGE_FACTOR.powf(clock as f32 /
+                                                                 sample_rate as f32)
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        self.square_1.save_state(state);
+        self.square_2.save_state(state);
+        self.wave.save_state(state);
+        self.noise.save_state(state);
+        state.write_u8(self.nr50);
+        state.write_u8(self.nr51);
+        state.write_u8(self.frame_step);
+        state.write_u32(self.sample_counter);
+        state.write_f32(self.capacitor_left);
+        state.write_f32(self.capacitor_right)
+    }
+
+    // Samples that have not been flushed yet are dropped, the sample rate stays the host's
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.square_1.load_state(state)?;
+        self.square_2.load_state(state)?;
+        self.wave.load_state(state)?;
+        self.noise.load_state(state)?;
+        self.nr50 = state.read_u8()?;
+        self.nr51 = state.read_u8()?;
+        self.frame_step = state.read_u8()? & 0b111;
+        self.sample_counter = state.read_u32()?.min(CpuClock::Normal.value() - 1);
+        self.capacitor_left = state.read_f32()?;
+        self.capacitor_right = state.read_f32()?;
+        self.samples.clear();
+        Ok(())
     }
 
-    #[allow(unused_variables)]
-    pub fn write(&mut self, addr: u16, val: u8) {}
+    pub fn flush_samples(&mut self, audio_sink: &mut dyn AudioSink) {
+        if !self.samples.is_empty() {
+            audio_sink.samples_available(&self.samples);
+            self.samples.clear()
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, val: u8) {
+        if let 0xff30..=0xff3f = addr {
+            self.wave.wave_ram[(addr - 0xff30) as usize] = val;
+            return;
+        }
+
+        if addr == 0xff26 {
+            self.set_power((val & 0x80) != 0);
+            return;
+        }
+
+        if !self.enabled {
+            return;
+        }
+
+        let frame_step = self.frame_step;
+
+        match addr {
+            0xff10..=0xff14 => self.square_1.write(addr - 0xff10, val, frame_step),
+            0xff15..=0xff19 => self.square_2.write(addr - 0xff15, val, frame_step),
+            0xff1a..=0xff1e => self.wave.write(addr - 0xff1a, val, frame_step),
+            0xff1f..=0xff23 => self.noise.write(addr - 0xff1f, val, frame_step),
+            0xff24 => self.nr50 = val,
+            0xff25 => self.nr51 = val,
+            0xff27..=0xff2f => {}
+            _ => panic!("Address not in range 0x{:x}", addr),
+        }
+    }
 
-    #[allow(unused_variables)]
     pub fn read(&self, addr: u16) -> u8 {
-        0
+        match addr {
+            0xff10..=0xff14 => self.square_1.read(addr - 0xff10),
+            0xff15..=0xff19 => self.square_2.read(addr - 0xff15),
+            0xff1a..=0xff1e => self.wave.read(addr - 0xff1a),
+            0xff1f..=0xff23 => self.noise.read(addr - 0xff1f),
+            0xff24 => self.nr50,
+            0xff25 => self.nr51,
+            0xff26 => self.get_status(),
+            0xff27..=0xff2f => 0xff,
+            0xff30..=0xff3f => self.wave.wave_ram[(addr - 0xff30) as usize],
+            _ => panic!("Address not in range 0x{:x}", addr),
+        }
+    }
+
+    // Like read, but NRx3 and NRx4 also give the frequency bits that were last written
+    pub fn register(&self, addr: u16) -> u8 {
+        let frequency = match addr {
+            0xff13 | 0xff14 => self.square_1.frequency,
+            0xff18 | 0xff19 => self.square_2.frequency,
+            0xff1d | 0xff1e => self.wave.frequency,
+            _ => return self.read(addr),
+        };
+        match addr {
+            0xff13 | 0xff18 | 0xff1d => frequency as u8,
+            _ => (self.read(addr) & 0b0100_0000) | (frequency >> 8) as u8,
+        }
+    }
+
+    pub fn cycle_flush(&mut self, cycle_count: u32) {
+        if self.sample_rate == 0 {
+            self.step(cycle_count);
+            return;
+        }
+
+        let clock = CpuClock::Normal.value();
+        let mut cycles = cycle_count;
+        while cycles > 0 {
+            let until_sample = (clock - self.sample_counter).div_ceil(self.sample_rate);
+            let chunk = cycles.min(until_sample);
+
+            self.step(chunk);
+            cycles -= chunk;
+
+            self.sample_counter += chunk * self.sample_rate;
+            if self.sample_counter >= clock {
+                self.sample_counter -= clock;
+                self.push_sample()
+            }
+        }
+    }
+
+    fn step(&mut self, cycle_count: u32) {
+        if !self.enabled {
+            return;
+        }
+
+        self.square_1.step(cycle_count);
+        self.square_2.step(cycle_count);
+        self.wave.step(cycle_count);
+        self.noise.step(cycle_count);
+    }
+
+    fn push_sample(&mut self) {
+        let (left, right) = self.output();
+
+        let left_out = left - self.capacitor_left;
+        self.capacitor_left = left - left_out * self.high_pass_charge;
+
+        let right_out = right - self.capacitor_right;
+        self.capacitor_right = right - right_out * self.high_pass_charge;
+
+        self.samples.push(Spu::to_i16(left_out));
+        self.samples.push(Spu::to_i16(right_out))
+    }
+
+    fn to_i16(sample: f32) -> i16 {
+        (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16
+    }
+
+    // Returns the current (left, right) output, each in the range -1.0...1.0
+    fn output(&self) -> (f32, f32) {
+        if !self.enabled {
+            return (0.0, 0.0);
+        }
+
+        let channels = [self.dac_output(self.square_1.dac_enabled(), self.square_1.output()),
+                        self.dac_output(self.square_2.dac_enabled(), self.square_2.output()),
+                        self.dac_output(self.wave.dac_enabled, self.wave.output()),
+                        self.dac_output(self.noise.envelope.dac_enabled(), self.noise.output())];
+
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for (i, sample) in channels.iter().enumerate() {
+            if (self.nr51 & (0x10 << i)) != 0 {
+                left += sample
+            }
+            if (self.nr51 & (0x01 << i)) != 0 {
+                right += sample
+            }
+        }
+
+        let left_volume = (((self.nr50 >> 4) & 0b111) + 1) as f32 / 8.0;
+        let right_volume = ((self.nr50 & 0b111) + 1) as f32 / 8.0;
+
+        (left / 4.0 * left_volume, right / 4.0 * right_volume)
+    }
+
+    fn dac_output(&self, dac_enabled: bool, sample: u8) -> f32 {
+        if dac_enabled {
+            sample as f32 / 7.5 - 1.0
+        } else {
+            0.0
+        }
+    }
+
+    fn get_status(&self) -> u8 {
+        let mut flags = 0x70;
+        if self.enabled {
+            flags |= 0b1000_0000
+        }
+        if self.noise.enabled {
+            flags |= 0b0000_1000
+        }
+        if self.wave.enabled {
+            flags |= 0b0000_0100
+        }
+        if self.square_2.enabled {
+            flags |= 0b0000_0010
+        }
+        if self.square_1.enabled {
+            flags |= 0b0000_0001
+        }
+        flags
+    }
+
+    fn set_power(&mut self, enabled: bool) {
+        if self.enabled && !enabled {
+            // Powering off clears every register except wave RAM
+            self.square_1 = SquareChannel::new(true);
+            self.square_2 = SquareChannel::new(false);
+            self.noise = NoiseChannel::new();
+            let wave_ram = self.wave.wave_ram;
+            self.wave = WaveChannel::new();
+            self.wave.wave_ram = wave_ram;
+            self.nr50 = 0;
+            self.nr51 = 0
+        } else if !self.enabled && enabled {
+            self.frame_step = 0
+        }
+        self.enabled = enabled
+    }
+
+    // The frame sequencer is clocked at 512 Hz by the timer's system counter (DIV-APU)
+    pub fn clock_div_apu(&mut self) {
+        if self.enabled {
+            self.clock_frame_sequencer()
+        }
+    }
+
+    fn clock_frame_sequencer(&mut self) {
+        match self.frame_step {
+            0 | 4 => self.clock_length(),
+            2 | 6 => {
+                self.clock_length();
+                self.square_1.clock_sweep()
+            }
+            7 => {
+                self.square_1.envelope.clock();
+                self.square_2.envelope.clock();
+                self.noise.envelope.clock()
+            }
+            _ => {}
+        }
+        self.frame_step = (self.frame_step + 1) & 0b111
+    }
+
+    fn clock_length(&mut self) {
+        self.square_1.clock_length();
+        self.square_2.clock_length();
+        self.wave.clock_length();
+        self.noise.clock_length()
     }
 }
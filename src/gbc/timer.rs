@@ -1,47 +1,62 @@
-use std::u8;
 use super::Interrupts;
 use super::INT_TIMEROVERFLOW;
+use super::save_state::{StateWriter,StateReader,StateError};
 
-#[allow(dead_code)]
-const DIV_INC_RATE_0: u32 = 16384;
+// The bit of the system counter that clocks TIMA on its falling edge, for each TAC clock select
+const TIMA_BITS: [u16; 4] = [9, 3, 5, 7];
 
-#[allow(dead_code)]
-const DIV_INC_RATE_1: u32 = 32768;
+// The bit of the system counter that clocks the APU frame sequencer (DIV-APU) at 512 Hz
+const APU_BIT_NORMAL_SPEED: u16 = 12;
+const APU_BIT_DOUBLE_SPEED: u16 = 13;
 
-const CLOCKS: [u32; 4] = [1024, 16, 64, 256];
+const M_CYCLE: u32 = 4;
 
-#[derive(Debug)]
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+enum Reload {
+    Idle,
+    // TIMA overflowed and reads 0, it is reloaded from TMA on the next M-cycle
+    Overflowed,
+    // TIMA was reloaded from TMA during the last M-cycle
+    Reloading,
+}
+
+#[derive(Debug,Clone)]
 pub struct Timer {
-    div: u8,
-    div_cycles: u8,
+    // 16-bit system counter, DIV is its upper byte
+    counter: u16,
+    cycles: u32,
     tima: u8,
-    tima_cycles: u32,
     tma: u8,
     enabled: bool,
     clock_select: u8,
-    clock_rate: u32,
+    reload: Reload,
+    apu_bit: u16,
+    apu_ticks: u32,
 }
 
 impl Timer {
     pub fn new() -> Timer {
         Timer {
-            div: 0,
-            div_cycles: 0,
+            counter: 0,
+            cycles: 0,
             tima: 0,
-            tima_cycles: 0,
             tma: 0,
             enabled: false,
             clock_select: 0,
-            clock_rate: CLOCKS[0],
+            reload: Reload::Idle,
+            apu_bit: APU_BIT_NORMAL_SPEED,
+            apu_ticks: 0,
         }
     }
 
     pub fn read(&self, addr: u16) -> u8 {
         match addr {
-            0xff04 => self.div,
+            0xff04 => (self.counter >> 8) as u8,
             0xff05 => self.tima,
             0xff06 => self.tma,
-            0xff07 => (self.clock_select & 0b11) | if self.enabled { 0b100 } else { 0 },
+            0xff07 => {
+                0b1111_1000 | (self.clock_select & 0b11) | if self.enabled { 0b100 } else { 0 }
+            }
 
             _ => panic!("Address not in range 0x{:x}", addr),
         }
@@ -49,61 +64,252 @@ impl Timer {
 
     pub fn write(&mut self, addr: u16, val: u8) {
         match addr {
-            0xff04 => self.div = 0,
-            0xff05 => self.tima = val,
-            0xff06 => self.tma = val,
+            // Resetting the counter can cause a falling edge on the TIMA and DIV-APU inputs
+            0xff04 => self.set_counter(0),
+            0xff05 => {
+                match self.reload {
+                    // Writing in the M-cycle after an overflow cancels the reload
+                    Reload::Overflowed => {
+                        self.reload = Reload::Idle;
+                        self.tima = val
+                    }
+                    // Writes are ignored while TIMA is loaded from TMA
+                    Reload::Reloading => {}
+                    Reload::Idle => self.tima = val,
+                }
+            }
+            0xff06 => {
+                self.tma = val;
+                if self.reload == Reload::Reloading {
+                    self.tima = val
+                }
+            }
             0xff07 => {
+                // Changing TAC can cause a falling edge on the TIMA input
+                let input = self.timer_input();
                 self.clock_select = val & 0b11;
                 self.enabled = (val & 0b100) != 0;
-                self.clock_rate = CLOCKS[self.clock_select as usize]
+                if input && !self.timer_input() {
+                    self.increment_tima()
+                }
             }
 
             _ => panic!("Address not in range 0x{:x}", addr),
         }
     }
 
-    pub fn cycle_flush(&mut self, cycle_count: u32) -> Interrupts {
-        self.flush_div(cycle_count);
-
-        if self.flush_tima(cycle_count) {
-            INT_TIMEROVERFLOW
+    // The DIV-APU input moves to a higher counter bit in double speed mode, so the frame
+    // sequencer keeps its rate
+    pub fn set_double_speed(&mut self, double_speed: bool) {
+        self.apu_bit = if double_speed {
+            APU_BIT_DOUBLE_SPEED
         } else {
-            Interrupts::empty()
+            APU_BIT_NORMAL_SPEED
         }
     }
 
-    fn flush_tima(&mut self, cycle_count: u32) -> bool {
-        let tima_cycles = self.tima_cycles + cycle_count;
-        let rate = self.clock_rate;
-        let ticks = tima_cycles / rate;
+    // Returns the number of APU frame sequencer clocks since the last call
+    pub fn take_apu_ticks(&mut self) -> u32 {
+        let ticks = self.apu_ticks;
+        self.apu_ticks = 0;
+        ticks
+    }
+
+    pub fn cycle_flush(&mut self, cycle_count: u32) -> Interrupts {
+        let mut interrupt = Interrupts::empty();
 
-        self.tima_cycles = tima_cycles - rate * ticks;
+        self.cycles += cycle_count;
+        while self.cycles >= M_CYCLE {
+            self.cycles -= M_CYCLE;
 
-        if self.enabled {
-            let (tima, overflow) = self.tima.overflowing_add(ticks as u8);
-            self.tima = if overflow {
-                self.tma.wrapping_add(tima)
-            } else {
-                tima
-            };
-            overflow
-        } else {
-            false
+            match self.reload {
+                Reload::Overflowed => {
+                    self.tima = self.tma;
+                    self.reload = Reload::Reloading;
+                    interrupt |= INT_TIMEROVERFLOW
+                }
+                Reload::Reloading => self.reload = Reload::Idle,
+                Reload::Idle => {}
+            }
+
+            let counter = self.counter.wrapping_add(M_CYCLE as u16);
+            self.set_counter(counter)
         }
+
+        interrupt
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.counter);
+        state.write_u32(self.cycles);
+        state.write_u8(self.tima);
+        state.write_u8(self.tma);
+        state.write_bool(self.enabled);
+        state.write_u8(self.clock_select);
+        state.write_u8(match self.reload {
+            Reload::Idle => 0,
+            Reload::Overflowed => 1,
+            Reload::Reloading => 2,
+        });
+        state.write_u16(self.apu_bit)
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.counter = state.read_u16()?;
+        self.cycles = state.read_u32()?;
+        self.tima = state.read_u8()?;
+        self.tma = state.read_u8()?;
+        self.enabled = state.read_bool()?;
+        self.clock_select = state.read_u8()? & 0b11;
+        self.reload = match state.read_u8()? {
+            0 => Reload::Idle,
+            1 => Reload::Overflowed,
+            2 => Reload::Reloading,
+            _ => return Err(StateError::InvalidFormat),
+        };
+        self.apu_bit = match state.read_u16()? {
+            APU_BIT_NORMAL_SPEED => APU_BIT_NORMAL_SPEED,
+            APU_BIT_DOUBLE_SPEED => APU_BIT_DOUBLE_SPEED,
+            _ => return Err(StateError::InvalidFormat),
+        };
+        self.apu_ticks = 0;
+        Ok(())
     }
 
-    fn flush_div(&mut self, cycle_count: u32) {
-        let div_ticks = cycle_count >> 8;
+    // Restores the registers from a state that only has their visible values, the lower bits
+    // of the system counter are lost
+    pub fn set_registers(&mut self, div: u8, tima: u8, tma: u8, tac: u8) {
+        self.counter = (div as u16) << 8;
+        self.cycles = 0;
+        self.tima = tima;
+        self.tma = tma;
+        self.enabled = (tac & 0b100) != 0;
+        self.clock_select = tac & 0b11;
+        self.reload = Reload::Idle;
+        self.apu_ticks = 0
+    }
+
+    fn set_counter(&mut self, counter: u16) {
+        let input = self.timer_input();
+        let apu_input = self.apu_input();
+
+        self.counter = counter;
 
-        self.div = self.div.wrapping_add(div_ticks as u8);
+        if input && !self.timer_input() {
+            self.increment_tima()
+        }
+        if apu_input && !self.apu_input() {
+            self.apu_ticks += 1
+        }
+    }
 
-        let div_inc_ticks = (cycle_count - (div_ticks << 8)) as u8;
-        let (div_cycles, overflow) = self.div_cycles.overflowing_add(div_inc_ticks);
+    fn timer_input(&self) -> bool {
+        let bit = TIMA_BITS[self.clock_select as usize];
+        self.enabled && (self.counter >> bit) & 0b1 != 0
+    }
 
-        self.div_cycles = div_cycles;
+    fn apu_input(&self) -> bool {
+        (self.counter >> self.apu_bit) & 0b1 != 0
+    }
 
+    fn increment_tima(&mut self) {
+        let (tima, overflow) = self.tima.overflowing_add(1);
+        self.tima = tima;
         if overflow {
-            self.div = self.div.wrapping_add(1)
+            self.reload = Reload::Overflowed
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A timer that is enabled and clocks TIMA every 16 cycles, with the counter at zero
+    fn fast_timer() -> Timer {
+        let mut timer = Timer::new();
+        timer.write(0xff07, 0b101);
+        timer
+    }
+
+    #[test]
+    fn tima_counts_falling_edges() {
+        let mut timer = fast_timer();
+        timer.cycle_flush(15);
+        assert_eq!(timer.read(0xff05), 0);
+        timer.cycle_flush(1);
+        assert_eq!(timer.read(0xff05), 1);
+        timer.cycle_flush(16 * 9);
+        assert_eq!(timer.read(0xff05), 10);
+    }
+
+    #[test]
+    fn div_reset_clocks_tima_on_falling_edge() {
+        let mut timer = fast_timer();
+        timer.cycle_flush(8);
+        timer.write(0xff04, 0);
+        assert_eq!(timer.read(0xff05), 1);
+
+        // No edge when the selected bit is clear
+        timer.cycle_flush(4);
+        timer.write(0xff04, 0);
+        assert_eq!(timer.read(0xff05), 1);
+    }
+
+    #[test]
+    fn tac_change_clocks_tima_on_falling_edge() {
+        let mut timer = fast_timer();
+        timer.cycle_flush(8);
+        timer.write(0xff07, 0b001);
+        assert_eq!(timer.read(0xff05), 1);
+
+        // Disabling the timer with the input low has no effect
+        let mut timer = fast_timer();
+        timer.write(0xff07, 0b001);
+        assert_eq!(timer.read(0xff05), 0);
+    }
+
+    #[test]
+    fn overflow_reloads_one_m_cycle_late() {
+        let mut timer = fast_timer();
+        timer.write(0xff06, 0x42);
+        timer.write(0xff05, 0xff);
+
+        assert!(timer.cycle_flush(16).is_empty());
+        assert_eq!(timer.read(0xff05), 0);
+
+        assert_eq!(timer.cycle_flush(4), INT_TIMEROVERFLOW);
+        assert_eq!(timer.read(0xff05), 0x42);
+    }
+
+    #[test]
+    fn tima_write_after_overflow_cancels_reload() {
+        let mut timer = fast_timer();
+        timer.write(0xff06, 0x42);
+        timer.write(0xff05, 0xff);
+        timer.cycle_flush(16);
+
+        timer.write(0xff05, 0x10);
+        assert!(timer.cycle_flush(4).is_empty());
+        assert_eq!(timer.read(0xff05), 0x10);
+    }
+
+    #[test]
+    fn writes_during_reload() {
+        let mut timer = fast_timer();
+        timer.write(0xff06, 0x42);
+        timer.write(0xff05, 0xff);
+        timer.cycle_flush(20);
+
+        // TIMA writes are ignored, TMA writes go through to TIMA
+        timer.write(0xff05, 0x10);
+        assert_eq!(timer.read(0xff05), 0x42);
+        timer.write(0xff06, 0x24);
+        assert_eq!(timer.read(0xff05), 0x24);
+
+        // After the reload M-cycle TIMA can be written again
+        timer.cycle_flush(4);
+        timer.write(0xff05, 0x10);
+        assert_eq!(timer.read(0xff05), 0x10);
+    }
+}
@@ -5,6 +5,7 @@ extern crate sdl2;
 use std::env;
 use std::path::PathBuf;
 use std::boxed::Box;
+use std::fs;
 use std::fs::File;
 use std::io::{Read, Write};
 
@@ -15,9 +16,17 @@ fn load_bin(path: &PathBuf) -> Box<[u8]> {
     bytes.into_boxed_slice()
 }
 
+// The bytes go to a temporary file that then replaces the old one, so quitting or crashing
+// halfway never leaves a truncated file behind
 fn save_bin(path: &PathBuf, bytes: Box<[u8]>) {
-    let mut file = File::create(path).unwrap();
+    let mut tmp_path = path.clone().into_os_string();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let mut file = File::create(&tmp_path).unwrap();
     file.write_all(&bytes).unwrap();
+    file.sync_all().unwrap();
+    fs::rename(&tmp_path, path).unwrap();
 }
 
 use sdl2::pixels::PixelFormatEnum;
@@ -25,18 +34,43 @@ use sdl2::rect::Rect;
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
 use sdl2::render::Texture;
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
 
 const WIDTH: usize = 160;
 const HEIGHT: usize = 144;
 
 const SCALE: usize = 4;
 
+const AUDIO_SAMPLE_RATE: i32 = 48000;
+const AUDIO_BUFFER_SAMPLES: u16 = 1024;
+
 const WINDOW_WIDTH: usize = WIDTH * SCALE;
 const WINDOW_HEIGHT: usize = HEIGHT * SCALE;
 
+// Changed battery backed RAM is saved at least this often, in frames (about 5 seconds)
+const SAVE_RAM_INTERVAL: u32 = 300;
+
+// Rewind history defaults, a snapshot every few frames in up to 64 MiB
+const REWIND_INTERVAL: u32 = 4;
+const REWIND_MEMORY_MB: usize = 64;
+
+const BUTTONS: [Button; 8] = [Button::Up,
+                              Button::Down,
+                              Button::Left,
+                              Button::Right,
+                              Button::A,
+                              Button::B,
+                              Button::Start,
+                              Button::Select];
+
 mod gbc;
+mod pacing;
+mod rewind;
 
-use gbc::console::{Console,Button,ButtonState,InputEvent,Cart};
+use pacing::{Pacer,AudioOutput,FrameTimer,Silence};
+use rewind::Rewind;
+use gbc::console::{Console,Button,ButtonState,InputEvent,Cart,GameboyType,Palette,PalettePreset,
+                   Renderer};
 
 fn make_events(current: &Vec<Keycode>, prev: &Vec<Keycode>) -> Vec<InputEvent> {
 
@@ -60,7 +94,7 @@ fn make_events(current: &Vec<Keycode>, prev: &Vec<Keycode>) -> Vec<InputEvent> {
 }
 
 impl<'a> gbc::console::VideoSink for Texture<'a> {
-    fn frame_available(&mut self, frame: &Box<[u32]>) {
+    fn frame_available(&mut self, frame: &[u32]) {
         unsafe {
             let size = frame.len() * 4;
             let frame = std::slice::from_raw_parts(frame.as_ptr() as *const u8, size);
@@ -86,10 +120,25 @@ pub fn main() -> Result<(), String> {
     
     let texture_creator = canvas.texture_creator();
 
-    let mut texture: Texture = texture_creator.create_texture_streaming(PixelFormatEnum::RGBA32, WIDTH as _, HEIGHT as _)
+    let mut texture: Texture = texture_creator.create_texture_streaming(PixelFormatEnum::ARGB8888, WIDTH as _, HEIGHT as _)
         .map_err(|e| e.to_string())?;
 
-    let rom_path = PathBuf::from(env::args().nth(1).unwrap());
+    let args: Vec<String> = env::args().skip(1).collect();
+    let audio_enabled = !args.iter().any(|a| a == "--no-audio");
+
+    let mut pacer = if audio_enabled {
+        match open_audio(&sdl_context) {
+            Ok(audio_queue) => Pacer::Audio(AudioOutput::new(audio_queue)),
+            Err(e) => {
+                println!("Audio unavailable, using wall-clock pacing: {}", e);
+                Pacer::WallClock(FrameTimer::new())
+            }
+        }
+    } else {
+        Pacer::WallClock(FrameTimer::new())
+    };
+
+    let rom_path = PathBuf::from(args.iter().find(|a| !a.starts_with("--")).unwrap());
     let rom_binary = load_bin(&rom_path);
 
     let save_ram_path = {
@@ -108,50 +157,241 @@ pub fn main() -> Result<(), String> {
 
     println!("{:?}", cart);
 
-    let mut console = Console::new(cart);
+    let gb_type = if args.iter().any(|a| a == "--dmg") {
+        Some(GameboyType::Dmg)
+    } else if args.iter().any(|a| a == "--cgb") {
+        Some(GameboyType::Cgb)
+    } else {
+        None
+    };
 
-    let mut event_pump = sdl_context.event_pump()?;
+    let boot_rom = args.iter()
+        .find(|a| a.starts_with("--boot-rom="))
+        .map(|a| load_bin(&PathBuf::from(&a["--boot-rom=".len()..])));
+
+    let mut console = Console::new(cart, gb_type, boot_rom).map_err(|e| e.to_string())?;
+    let battery = console.has_battery();
+
+    if args.iter().any(|a| a == "--rtc-sync") {
+        console.sync_rtc()
+    }
+    let mut frames_since_save = 0;
+
+    if args.iter().any(|a| a == "--pixel-fifo") {
+        console.set_renderer(Renderer::PixelFifo)
+    }
 
-    let sleep_time = std::time::Duration::from_millis(16);
+    if args.iter().any(|a| a == "--no-sprite-limit") {
+        console.set_sprite_limit(false)
+    }
+
+    if args.iter().any(|a| a == "--no-access-restrictions") {
+        console.set_access_restrictions(false)
+    }
+
+    let mut palette_preset = args.iter()
+        .find(|a| a.starts_with("--palette="))
+        .map(|a| {
+            let name = &a["--palette=".len()..];
+            PalettePreset::from_name(name)
+                .unwrap_or_else(|| panic!("Unknown palette: {}", name))
+        });
+    if let Some(preset) = palette_preset {
+        let palette = console.preset_palette(preset);
+        console.set_palette(palette)
+    }
+
+    // A palette file next to the rom overrides the palette for that game
+    let palette_path = {
+        let mut path = rom_path.clone();
+        path.set_extension("palette");
+        path
+    };
+    if palette_path.exists() {
+        let contents = String::from_utf8(load_bin(&palette_path).into_vec()).unwrap();
+        match parse_palette(&contents) {
+            Some(PaletteSetting::Preset(preset)) => {
+                palette_preset = Some(preset);
+                let palette = console.preset_palette(preset);
+                console.set_palette(palette)
+            }
+            Some(PaletteSetting::Custom(palette)) => console.set_palette(palette),
+            None => println!("Invalid palette file: {:?}", palette_path),
+        }
+    }
+
+    let state_path = {
+        let mut path = rom_path.clone();
+        path.set_extension("state");
+        path
+    };
+    let bess_path = {
+        let mut path = rom_path.clone();
+        path.set_extension("bess");
+        path
+    };
+
+    // Holding backspace rewinds, --rewind-memory=0 turns the history off
+    let rewind_interval = args.iter()
+        .find(|a| a.starts_with("--rewind-interval="))
+        .map_or(REWIND_INTERVAL, |a| a["--rewind-interval=".len()..].parse().unwrap());
+    let rewind_memory = args.iter()
+        .find(|a| a.starts_with("--rewind-memory="))
+        .map_or(REWIND_MEMORY_MB, |a| a["--rewind-memory=".len()..].parse().unwrap());
+    let mut rewind = if rewind_memory > 0 {
+        Some(Rewind::new(rewind_interval, rewind_memory * 1024 * 1024))
+    } else {
+        None
+    };
+
+    let mut event_pump = sdl_context.event_pump()?;
 
     let mut prev_keys: Vec<Keycode> = Vec::new();
 
     'running: loop {
-        let now = std::time::Instant::now();
-
         for event in event_pump.poll_iter() {
-            if let Event::Quit { .. } = event {
-                break 'running
+            match event {
+                Event::Quit { .. } => break 'running,
+                Event::KeyDown { keycode: Some(Keycode::P), repeat: false, .. } => {
+                    let preset = palette_preset.map_or(PalettePreset::DmgGreen, |p| p.next());
+                    palette_preset = Some(preset);
+                    let palette = console.preset_palette(preset);
+                    console.set_palette(palette)
+                }
+                Event::KeyDown { keycode: Some(Keycode::F5), repeat: false, .. } => {
+                    save_bin(&state_path, console.save_state().into_boxed_slice());
+                    println!("Saved state to {:?}", state_path)
+                }
+                Event::KeyDown { keycode: Some(Keycode::F9), repeat: false, .. }
+                    if state_path.exists() => {
+                    match console.load_state(&load_bin(&state_path)) {
+                        Ok(()) => println!("Loaded state from {:?}", state_path),
+                        Err(e) => println!("Could not load {:?}: {}", state_path, e),
+                    }
+                }
+                Event::KeyDown { keycode: Some(Keycode::F6), repeat: false, .. } => {
+                    save_bin(&bess_path, console.export_bess().into_boxed_slice());
+                    println!("Exported state to {:?}", bess_path)
+                }
+                Event::KeyDown { keycode: Some(Keycode::F10), repeat: false, .. }
+                    if bess_path.exists() => {
+                    match console.import_bess(&load_bin(&bess_path)) {
+                        Ok(()) => println!("Imported state from {:?}", bess_path),
+                        Err(e) => println!("Could not import {:?}: {}", bess_path, e),
+                    }
+                }
+                _ => {}
             }
         }
 
-        let keys = event_pump
+        let keys: Vec<Keycode> = event_pump
             .keyboard_state()
             .pressed_scancodes()
             .filter_map(Keycode::from_scancode)
             .collect();
-    
-        make_events(&keys, &prev_keys)
-            .into_iter()
-            .for_each(|e| console.handle_event(e));
-        prev_keys = keys;
-    
-        console.run_for_one_frame(&mut texture);
+
+        match rewind {
+            Some(ref mut rewind) if keys.contains(&Keycode::Backspace) => {
+                if rewind.step_back(&mut console) {
+                    // The snapshot has its own buttons held, they are released so the keys
+                    // held when rewinding stops are pressed again
+                    for &button in BUTTONS.iter() {
+                        console.handle_event(InputEvent::new(button, ButtonState::Up))
+                    }
+                    prev_keys.clear();
+                    console.run_for_one_frame(&mut texture, &mut Silence)
+                }
+                pacer.play_silence()
+            }
+            _ => {
+                make_events(&keys, &prev_keys)
+                    .into_iter()
+                    .for_each(|e| console.handle_event(e));
+                prev_keys = keys;
+
+                console.run_for_one_frame(&mut texture, pacer.audio_sink());
+
+                if let Some(ref mut rewind) = rewind {
+                    rewind.frame_done(&console)
+                }
+            }
+        }
+
+        // Games disable RAM when they are done saving, otherwise changes are picked up within
+        // a few seconds
+        if battery {
+            frames_since_save += 1;
+            if console.take_cart_ram_disabled() ||
+               (console.cart_ram_dirty() && frames_since_save >= SAVE_RAM_INTERVAL) {
+                save_cart_ram(&mut console, &save_ram_path);
+                frames_since_save = 0
+            }
+        }
 
         canvas.clear();
         canvas.copy(&texture, None, Some(Rect::new(0, 0, WINDOW_WIDTH as _, WINDOW_HEIGHT as _)))?;
         canvas.present();
 
-        let elapsed = now.elapsed();
-        if sleep_time > elapsed {
-            let sleep = sleep_time - elapsed;
-            std::thread::sleep(sleep)
-        }
+        pacer.wait_for_next_frame()
+    }
+
+    if battery && (console.cart_ram_dirty() || console.has_rtc()) {
+        save_cart_ram(&mut console, &save_ram_path)
     }
 
     Ok(())
 }
 
+fn save_cart_ram(console: &mut Console, path: &PathBuf) {
+    if let Some(ram) = console.copy_save_data() {
+        save_bin(path, ram)
+    }
+    console.mark_cart_ram_saved()
+}
+
+fn open_audio(sdl_context: &sdl2::Sdl) -> Result<AudioQueue<i16>, String> {
+    let audio_subsystem = sdl_context.audio()?;
+
+    let desired_spec = AudioSpecDesired {
+        freq: Some(AUDIO_SAMPLE_RATE),
+        channels: Some(2),
+        samples: Some(AUDIO_BUFFER_SAMPLES),
+    };
+
+    let audio_queue: AudioQueue<i16> = audio_subsystem.open_queue(None, &desired_spec)?;
+    audio_queue.resume();
+    Ok(audio_queue)
+}
+
+enum PaletteSetting {
+    Preset(PalettePreset),
+    Custom(Palette),
+}
+
+// A palette file holds either a preset name, or 12 colors in hex (0xRRGGBB or RRGGBB),
+// 4 each for BG, OBJ0 and OBJ1 from lightest to darkest
+fn parse_palette(contents: &str) -> Option<PaletteSetting> {
+    let contents = contents.trim();
+    if let Some(preset) = PalettePreset::from_name(contents) {
+        return Some(PaletteSetting::Preset(preset));
+    }
+
+    let colors: Vec<u32> = contents.split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|s| !s.is_empty())
+        .map(|s| u32::from_str_radix(s.trim_start_matches("0x").trim_start_matches('#'), 16))
+        .collect::<Result<_, _>>()
+        .ok()?;
+    if colors.len() != 12 || colors.iter().any(|&c| c > 0xffffff) {
+        return None;
+    }
+
+    let mut sets = [[0; 4]; 3];
+    for (i, &color) in colors.iter().enumerate() {
+        sets[i / 4][i % 4] = color
+    }
+    Some(PaletteSetting::Custom(Palette::new(sets[0], sets[1], sets[2])))
+}
+
 trait IntoButton {
     fn into_button(self) -> Option<Button>;
 }
@@ -0,0 +1,159 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use sdl2::audio::AudioQueue;
+
+use crate::gbc::CpuClock;
+use crate::gbc::console::{AudioSink, CLKS_SCREEN_REFRESH};
+
+// Bytes per stereo i16 sample frame
+const BYTES_PER_SAMPLE: u32 = 2 * 2;
+
+// Audio latency the audio pacer aims to keep queued
+const AUDIO_TARGET_LATENCY_MS: u32 = 50;
+
+// Maximum deviation from the nominal sample rate used to steer the queue fill level
+const AUDIO_MAX_RATE_DELTA: f64 = 0.005;
+
+// Wall-clock pacing gives up on catching up when it falls this many frames behind
+const MAX_FRAMES_BEHIND: u32 = 4;
+
+// Sleeping is only accurate to about a millisecond, the remainder is spent spinning
+const SPIN_THRESHOLD: Duration = Duration::from_millis(2);
+
+pub fn frame_duration() -> Duration {
+    let nanos = CLKS_SCREEN_REFRESH as u64 * 1_000_000_000 / CpuClock::Normal.value() as u64;
+    Duration::from_nanos(nanos)
+}
+
+pub enum Pacer {
+    // Emulation is synced to the audio queue fill level, using dynamic rate control
+    Audio(AudioOutput),
+    // Emulation is synced to a precise wall clock when there is no audio output
+    WallClock(FrameTimer),
+}
+
+impl Pacer {
+    pub fn audio_sink(&mut self) -> &mut dyn AudioSink {
+        match *self {
+            Pacer::Audio(ref mut audio_output) => audio_output,
+            Pacer::WallClock(ref mut frame_timer) => frame_timer,
+        }
+    }
+
+    // Keeps the audio queue filled for frames that produce no samples
+    pub fn play_silence(&mut self) {
+        if let Pacer::Audio(ref mut audio_output) = *self {
+            audio_output.play_silence()
+        }
+    }
+
+    pub fn wait_for_next_frame(&mut self) {
+        match *self {
+            Pacer::Audio(ref mut audio_output) => audio_output.wait(),
+            Pacer::WallClock(ref mut frame_timer) => frame_timer.wait(),
+        }
+    }
+}
+
+pub struct AudioOutput {
+    queue: AudioQueue<i16>,
+    target_queued: u32,
+    rate: u32,
+}
+
+impl AudioOutput {
+    pub fn new(queue: AudioQueue<i16>) -> AudioOutput {
+        let freq = queue.spec().freq as u32;
+        let target_queued = freq * AUDIO_TARGET_LATENCY_MS / 1000 * BYTES_PER_SAMPLE;
+        AudioOutput {
+            queue,
+            target_queued,
+            rate: freq,
+        }
+    }
+
+    fn play_silence(&mut self) {
+        let samples = self.rate as u64 * CLKS_SCREEN_REFRESH as u64 /
+                      CpuClock::Normal.value() as u64;
+        let _ = self.queue.queue_audio(&vec![0; samples as usize * 2]);
+    }
+
+    fn wait(&mut self) {
+        while self.queue.size() > self.target_queued {
+            thread::sleep(Duration::from_millis(1))
+        }
+        self.update_rate()
+    }
+
+    // Produces slightly more samples when the queue runs low and slightly fewer when it
+    // fills up, so the queue settles around the target without audible pitch changes
+    fn update_rate(&mut self) {
+        let freq = self.queue.spec().freq as f64;
+        let fill = self.queue.size() as f64 / (2 * self.target_queued) as f64;
+        let delta = (1.0 - 2.0 * fill.min(1.0)) * AUDIO_MAX_RATE_DELTA;
+        self.rate = (freq * (1.0 + delta)).round() as u32
+    }
+}
+
+impl AudioSink for AudioOutput {
+    fn sample_rate(&self) -> u32 {
+        self.rate
+    }
+
+    fn samples_available(&mut self, samples: &[i16]) {
+        let _ = self.queue.queue_audio(samples);
+    }
+}
+
+pub struct FrameTimer {
+    frame_duration: Duration,
+    next_frame: Instant,
+}
+
+impl FrameTimer {
+    pub fn new() -> FrameTimer {
+        FrameTimer {
+            frame_duration: frame_duration(),
+            next_frame: Instant::now(),
+        }
+    }
+
+    fn wait(&mut self) {
+        self.next_frame += self.frame_duration;
+
+        let now = Instant::now();
+        if now > self.next_frame + self.frame_duration * MAX_FRAMES_BEHIND {
+            self.next_frame = now;
+            return;
+        }
+
+        if self.next_frame > now + SPIN_THRESHOLD {
+            thread::sleep(self.next_frame - now - SPIN_THRESHOLD)
+        }
+
+        while Instant::now() < self.next_frame {
+            thread::yield_now()
+        }
+    }
+}
+
+// Frames run with this sink generate no samples
+pub struct Silence;
+
+impl AudioSink for Silence {
+    fn sample_rate(&self) -> u32 {
+        0
+    }
+
+    fn samples_available(&mut self, _samples: &[i16]) {}
+}
+
+// Without audio output no samples are generated
+impl AudioSink for FrameTimer {
+    fn sample_rate(&self) -> u32 {
+        0
+    }
+
+    fn samples_available(&mut self, _samples: &[i16]) {}
+}
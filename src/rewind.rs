@@ -0,0 +1,237 @@
+use std::collections::VecDeque;
+
+use crate::gbc::console::Console;
+
+// Rewind history. A save state is taken every few frames, only the newest one is kept whole.
+// Older ones are stored as deltas: the XOR with the next newer state, run-length encoded.
+// Little of a state changes between two snapshots, so the deltas are mostly zero runs.
+pub struct Rewind {
+    interval: u32,
+    max_bytes: usize,
+    // Frames run since the console was at the current snapshot
+    frames: u32,
+    // Frames left before the next step back while rewinding
+    hold: u32,
+    current: Option<Vec<u8>>,
+    // Deltas from each snapshot to the one before it, the newest at the back
+    deltas: VecDeque<Vec<u8>>,
+    delta_bytes: usize,
+}
+
+impl Rewind {
+    // The oldest snapshots are dropped once the history takes more than max_bytes
+    pub fn new(interval: u32, max_bytes: usize) -> Rewind {
+        Rewind {
+            interval: interval.max(1),
+            max_bytes,
+            frames: 0,
+            hold: 0,
+            current: None,
+            deltas: VecDeque::new(),
+            delta_bytes: 0,
+        }
+    }
+
+    // Call after every frame that ran normally
+    pub fn frame_done(&mut self, console: &Console) {
+        self.hold = 0;
+        self.frames += 1;
+        if self.frames < self.interval {
+            return;
+        }
+        self.frames = 0;
+
+        let state = console.save_state();
+        if let Some(ref current) = self.current {
+            let delta = encode_delta(&state, current);
+            self.delta_bytes += delta.len();
+            self.deltas.push_back(delta)
+        }
+        self.current = Some(state);
+
+        let current_bytes = self.current.as_ref().map_or(0, |current| current.len());
+        while self.delta_bytes + current_bytes > self.max_bytes {
+            match self.deltas.pop_front() {
+                Some(delta) => self.delta_bytes -= delta.len(),
+                None => break,
+            }
+        }
+    }
+
+    // Call every frame while rewinding. The console is moved back one snapshot every interval
+    // frames, so the game goes backwards at normal speed. Returns true when the console was
+    // moved, false while waiting or when the history is used up.
+    pub fn step_back(&mut self, console: &mut Console) -> bool {
+        if self.hold > 0 {
+            self.hold -= 1;
+            return false;
+        }
+
+        let current = match self.current {
+            Some(ref mut current) => current,
+            None => return false,
+        };
+
+        // When the console is still at the current snapshot, go to the one before it
+        if self.frames == 0 {
+            match self.deltas.pop_back() {
+                Some(delta) => {
+                    self.delta_bytes -= delta.len();
+                    *current = apply_delta(current, &delta)
+                }
+                None => return false,
+            }
+        }
+
+        // Older snapshots are only reachable through this one, so if it doesn't load the
+        // history ends here
+        if console.load_state(current).is_err() {
+            self.clear();
+            return false;
+        }
+        self.frames = 0;
+        self.hold = self.interval - 1;
+        true
+    }
+
+    fn clear(&mut self) {
+        self.frames = 0;
+        self.current = None;
+        self.deltas.clear();
+        self.delta_bytes = 0
+    }
+}
+
+// A delta is the length of the target state, followed by runs of a u16 count of unchanged
+// bytes, a u16 count of changed bytes and the changed bytes XORed with the base. States can
+// differ in length, the shorter one is padded with zeros.
+fn encode_delta(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let len = base.len().max(target.len());
+    let xor = |i: usize| base.get(i).unwrap_or(&0) ^ target.get(i).unwrap_or(&0);
+
+    let mut delta = Vec::new();
+    push_u16(&mut delta, target.len() as u16);
+    push_u16(&mut delta, (target.len() >> 16) as u16);
+
+    let mut i = 0;
+    while i < len {
+        let zeros_start = i;
+        while i < len && xor(i) == 0 && i - zeros_start < 0xffff {
+            i += 1
+        }
+        push_u16(&mut delta, (i - zeros_start) as u16);
+
+        let changed_start = i;
+        while i < len && xor(i) != 0 && i - changed_start < 0xffff {
+            i += 1
+        }
+        push_u16(&mut delta, (i - changed_start) as u16);
+        for j in changed_start..i {
+            delta.push(xor(j))
+        }
+    }
+    delta
+}
+
+fn apply_delta(base: &[u8], delta: &[u8]) -> Vec<u8> {
+    let target_len = read_u16(delta, 0) as usize | (read_u16(delta, 2) as usize) << 16;
+
+    let mut state = base.to_vec();
+    state.resize(base.len().max(target_len), 0);
+
+    let mut pos = 4;
+    let mut i = 0;
+    while pos < delta.len() {
+        i += read_u16(delta, pos) as usize;
+        let changed = read_u16(delta, pos + 2) as usize;
+        pos += 4;
+        for &b in delta[pos..pos + changed].iter() {
+            state[i] ^= b;
+            i += 1
+        }
+        pos += changed
+    }
+
+    state.truncate(target_len);
+    state
+}
+
+fn push_u16(bytes: &mut Vec<u8>, val: u16) {
+    bytes.push(val as u8);
+    bytes.push((val >> 8) as u8)
+}
+
+fn read_u16(bytes: &[u8], pos: usize) -> u16 {
+    (bytes[pos] as u16) | ((bytes[pos + 1] as u16) << 8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gbc::console::{Cart,GameboyType};
+
+    fn roundtrip(base: &[u8], target: &[u8]) {
+        let delta = encode_delta(base, target);
+        assert_eq!(apply_delta(base, &delta), target);
+    }
+
+    #[test]
+    fn delta_roundtrip() {
+        roundtrip(&[], &[]);
+        roundtrip(&[1, 2, 3, 4], &[1, 2, 3, 4]);
+        roundtrip(&[1, 2, 3, 4, 5, 6], &[1, 0, 3, 9, 9, 6]);
+        roundtrip(&[0; 8], &[0xff; 8]);
+    }
+
+    #[test]
+    fn delta_roundtrip_with_different_lengths() {
+        roundtrip(&[1, 2, 3], &[1, 2, 3, 4, 5]);
+        roundtrip(&[1, 2, 3, 4, 5], &[1, 2]);
+        roundtrip(&[1, 2, 3], &[1, 2, 3, 0, 0]);
+        roundtrip(&[], &[7; 10]);
+    }
+
+    #[test]
+    fn delta_roundtrip_with_long_runs() {
+        let base = vec![0x55; 0x30000];
+        let mut target = base.clone();
+        for b in target[0x100..0x20100].iter_mut() {
+            *b = 0xaa
+        }
+        target[0x2ffff] = 0;
+        roundtrip(&base, &target);
+        roundtrip(&target, &base);
+
+        // Unchanged states only need a run header every 0xffff bytes
+        assert!(encode_delta(&base, &base).len() < 32);
+    }
+
+    fn test_console() -> Console {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0100] = 0x18;
+        rom[0x0101] = 0xfe;
+        let cart = Cart::new(rom.into_boxed_slice(), None);
+        Console::new(cart, Some(GameboyType::Dmg), None).unwrap()
+    }
+
+    #[test]
+    fn snapshot_that_fails_to_load_ends_history() {
+        let mut console = test_console();
+        let mut rewind = Rewind::new(1, usize::MAX);
+        for _ in 0..3 {
+            rewind.frame_done(&console)
+        }
+        assert_eq!(rewind.deltas.len(), 2);
+
+        // The console ran past the current snapshot, so it is loaded as is
+        rewind.frames = 1;
+        rewind.current = Some(vec![0; 4]);
+        let before = console.save_state();
+        assert!(!rewind.step_back(&mut console));
+        assert_eq!(console.save_state(), before);
+        assert!(rewind.current.is_none());
+        assert!(rewind.deltas.is_empty());
+        assert_eq!(rewind.delta_bytes, 0);
+        assert!(!rewind.step_back(&mut console));
+    }
+}